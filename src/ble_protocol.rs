@@ -0,0 +1,291 @@
+//! The formats BLE clients write commands in, and the status they get back for them.
+//!
+//! A command is a few bytes, everything little endian:
+//!
+//! | byte | meaning                                     |
+//! |------|---------------------------------------------|
+//! | 0    | protocol version, `PROTOCOL_VERSION`        |
+//! | 1-2  | request id, picked by the client            |
+//! | 3    | action, 0 off, 1 on, 2 set                  |
+//! | 4    | target, only needed to set                  |
+//!
+//! Every command gets a status back on the status characteristic, `[version, request id
+//! (2 bytes), status code]`, see `StatusCode` for the codes.
+//!
+//! Writes of a single ASCII digit are the legacy text format instead, the digit being
+//! the target to set. Trailing NULs and newlines after it are ignored.
+use std::fmt;
+
+use device::Action;
+
+use crate::error::HubError;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Anything below this can't be text, so it's taken as a protocol version
+const FIRST_TEXT_BYTE: u8 = 0x20;
+const ACTION_OFF: u8 = 0;
+const ACTION_ON: u8 = 1;
+const ACTION_SET: u8 = 2;
+
+/// A command written in the binary format
+#[derive(Debug, Clone, Copy)]
+pub struct BleCommand {
+    pub request_id: u16,
+    pub action: Action,
+}
+
+/// What a write to a set characteristic turned out to be
+#[derive(Debug, Clone, Copy)]
+pub enum BleWrite {
+    /// A single digit target, e.g. "3"
+    Legacy(Action),
+    Binary(BleCommand),
+}
+
+/// Why a write couldn't be understood
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    UnsupportedVersion(u8),
+    TooShort,
+    UnknownAction(u8),
+    /// A legacy write that isn't a single digit
+    NotADigit,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DecodeError::*;
+        match self {
+            Empty => write!(f, "Nothing was written"),
+            UnsupportedVersion(version) => write!(
+                f,
+                "Protocol version {} isn't supported, only {} is",
+                version, PROTOCOL_VERSION
+            ),
+            TooShort => write!(f, "The command is missing bytes"),
+            UnknownAction(action) => write!(f, "{} isn't an action", action),
+            NotADigit => write!(f, "Expected a single digit target"),
+        }
+    }
+}
+
+impl DecodeError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            DecodeError::UnsupportedVersion(_) => StatusCode::UnsupportedVersion,
+            _ => StatusCode::Malformed,
+        }
+    }
+}
+
+/// Works out which format the write is in and what it asks for
+pub fn decode(payload: &[u8]) -> Result<BleWrite, DecodeError> {
+    let first = match payload.first() {
+        Some(first) => *first,
+        None => return Err(DecodeError::Empty),
+    };
+    if first >= FIRST_TEXT_BYTE {
+        let padding = payload[1..]
+            .iter()
+            .all(|byte| matches!(byte, b'\0' | b'\n'));
+        return match (first as char).to_digit(10) {
+            Some(target) if padding => Ok(BleWrite::Legacy(Action::Set {
+                target: target as usize,
+            })),
+            _ => Err(DecodeError::NotADigit),
+        };
+    }
+    if first != PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(first));
+    }
+    let request_id = match request_id(payload) {
+        Some(request_id) => request_id,
+        None => return Err(DecodeError::TooShort),
+    };
+    let action = match (payload.get(3), payload.get(4)) {
+        (Some(&ACTION_OFF), _) => Action::Off,
+        (Some(&ACTION_ON), _) => Action::On,
+        (Some(&ACTION_SET), Some(target)) => Action::Set {
+            target: *target as usize,
+        },
+        (Some(&ACTION_SET), None) | (None, _) => return Err(DecodeError::TooShort),
+        (Some(action), _) => return Err(DecodeError::UnknownAction(*action)),
+    };
+    Ok(BleWrite::Binary(BleCommand { request_id, action }))
+}
+
+/// The request id of a binary write, as long as there's enough of it to have one
+pub fn request_id(payload: &[u8]) -> Option<u16> {
+    match payload {
+        [version, low, high, ..] if *version < FIRST_TEXT_BYTE => {
+            Some(u16::from_le_bytes([*low, *high]))
+        }
+        _ => None,
+    }
+}
+
+/// How a binary command went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StatusCode {
+    Ok = 0,
+    Malformed = 1,
+    UnsupportedVersion = 2,
+    UnknownDevice = 3,
+    NodeUnreachable = 4,
+    NodeRejected = 5,
+    Timeout = 6,
+    HubUnavailable = 7,
+    InvalidNodeResponse = 8,
+    AmbiguousName = 9,
+    TargetOutOfRange = 10,
+    DeviceOffline = 11,
+}
+
+impl From<&HubError> for StatusCode {
+    fn from(error: &HubError) -> StatusCode {
+        use HubError::*;
+        match error {
+            InvalidCommand { .. } => StatusCode::Malformed,
+            UnknownName { .. } | UnknownDevice { .. } => StatusCode::UnknownDevice,
+            AmbiguousName { .. } => StatusCode::AmbiguousName,
+            TargetOutOfRange { .. } => StatusCode::TargetOutOfRange,
+            DeviceOffline { .. } => StatusCode::DeviceOffline,
+            NodeUnreachable { .. } => StatusCode::NodeUnreachable,
+            NodeRejected { .. } => StatusCode::NodeRejected,
+            Timeout => StatusCode::Timeout,
+            HubUnavailable { .. } => StatusCode::HubUnavailable,
+            InvalidNodeResponse { .. } => StatusCode::InvalidNodeResponse,
+        }
+    }
+}
+
+/// The status of one binary command, as sent back on the status characteristic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandStatus {
+    pub request_id: u16,
+    pub code: StatusCode,
+}
+
+impl CommandStatus {
+    pub fn encode(&self) -> Vec<u8> {
+        let [low, high] = self.request_id.to_le_bytes();
+        vec![PROTOCOL_VERSION, low, high, self.code as u8]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a write should decode to, the request id and action or the error
+    enum Expected {
+        Legacy(usize),
+        On(u16),
+        Off(u16),
+        Set(u16, usize),
+        Fails(DecodeError),
+    }
+
+    #[test]
+    fn decodes_writes() {
+        use Expected::*;
+        let cases: [(&[u8], Expected); 22] = [
+            (&[1, 0x34, 0x12, 1], On(0x1234)),
+            (&[1, 1, 0, 0], Off(1)),
+            (&[1, 2, 0, 2, 5], Set(2, 5)),
+            (&[1, 0xff, 0xff, 2, 0], Set(0xffff, 0)),
+            (b"3", Legacy(3)),
+            (b"0", Legacy(0)),
+            (b"7\n", Legacy(7)),
+            (b"7\0\0", Legacy(7)),
+            (b"7\n\0", Legacy(7)),
+            (b"", Fails(DecodeError::Empty)),
+            (b"34", Fails(DecodeError::NotADigit)),
+            (b"3 ", Fails(DecodeError::NotADigit)),
+            (b"3\r\n", Fails(DecodeError::NotADigit)),
+            (b"on", Fails(DecodeError::NotADigit)),
+            (&[0, 1, 0, 1], Fails(DecodeError::UnsupportedVersion(0))),
+            (&[2, 1, 0, 1], Fails(DecodeError::UnsupportedVersion(2))),
+            (&[1], Fails(DecodeError::TooShort)),
+            (&[1, 1], Fails(DecodeError::TooShort)),
+            (&[1, 1, 0], Fails(DecodeError::TooShort)),
+            (&[1, 1, 0, 2], Fails(DecodeError::TooShort)),
+            (&[1, 1, 0, 3], Fails(DecodeError::UnknownAction(3))),
+            (&[1, 1, 0, 0xff, 4], Fails(DecodeError::UnknownAction(0xff))),
+        ];
+        for (payload, expected) in cases {
+            let decoded = decode(payload);
+            match (&expected, &decoded) {
+                (Legacy(target), Ok(BleWrite::Legacy(Action::Set { target: t }))) => {
+                    assert_eq!(t, target, "{:?}", payload)
+                }
+                (On(id), Ok(BleWrite::Binary(c))) if matches!(c.action, Action::On) => {
+                    assert_eq!(c.request_id, *id, "{:?}", payload)
+                }
+                (Off(id), Ok(BleWrite::Binary(c))) if matches!(c.action, Action::Off) => {
+                    assert_eq!(c.request_id, *id, "{:?}", payload)
+                }
+                (Set(id, target), Ok(BleWrite::Binary(c))) => match c.action {
+                    Action::Set { target: t } => {
+                        assert_eq!(c.request_id, *id, "{:?}", payload);
+                        assert_eq!(t, *target, "{:?}", payload);
+                    }
+                    _ => panic!("{:?} decoded as {:?}", payload, c),
+                },
+                (Fails(error), Err(e)) => assert_eq!(e, error, "{:?}", payload),
+                _ => panic!("{:?} decoded as {:?}", payload, decoded),
+            }
+        }
+    }
+
+    #[test]
+    fn finds_request_ids() {
+        let cases: [(&[u8], Option<u16>); 6] = [
+            (&[1, 0x34, 0x12, 1], Some(0x1234)),
+            (&[1, 0x34, 0x12], Some(0x1234)),
+            // Unsupported versions still get a status back for their request
+            (&[9, 5, 0, 1], Some(5)),
+            (&[1, 0x34], None),
+            (&[], None),
+            (b"345", None),
+        ];
+        for (payload, id) in cases {
+            assert_eq!(request_id(payload), id, "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn statuses_carry_the_request_id() {
+        let cases = [
+            (0, StatusCode::Ok),
+            (0x1234, StatusCode::UnsupportedVersion),
+            (0xffff, StatusCode::DeviceOffline),
+        ];
+        for (id, code) in cases {
+            let encoded = CommandStatus {
+                request_id: id,
+                code,
+            }
+            .encode();
+            assert_eq!(encoded.len(), 4);
+            assert_eq!(encoded[0], PROTOCOL_VERSION);
+            assert_eq!(encoded[3], code as u8);
+            assert_eq!(request_id(&encoded), Some(id));
+        }
+        let command = [PROTOCOL_VERSION, 0x34, 0x12, ACTION_SET, 3];
+        match decode(&command) {
+            Ok(BleWrite::Binary(c)) => assert_eq!(
+                CommandStatus {
+                    request_id: c.request_id,
+                    code: StatusCode::Ok,
+                }
+                .encode(),
+                vec![PROTOCOL_VERSION, 0x34, 0x12, 0]
+            ),
+            decoded => panic!("{:?} decoded as {:?}", command, decoded),
+        }
+    }
+}
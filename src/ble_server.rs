@@ -1,334 +1,639 @@
 //! Serves a Bluetooth GATT application using the callback programming model.
+use std::fmt;
 use std::str::FromStr;
 use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use bluer::{
     adv::Advertisement,
     gatt::local::{
-        Application, Characteristic, CharacteristicNotify, CharacteristicNotifyMethod,
-        CharacteristicRead, CharacteristicWrite, CharacteristicWriteMethod, Service,
+        Application, ApplicationHandle, Characteristic, CharacteristicNotifier,
+        CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+        CharacteristicWriteMethod, ReqError, Service,
     },
-    Uuid,
+    Adapter, AdapterEvent, AdapterProperty, SessionEvent, Uuid,
 };
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
-    sync::{mpsc, Mutex},
+    sync::{broadcast, mpsc, watch, Mutex},
     time::sleep,
 };
 
-use device::{Action, Device, DeviceType};
+use device::Device;
 
+use crate::ble_protocol::{self, BleWrite, CommandStatus, StatusCode};
+use crate::command_parser;
+use crate::config::BleConfig;
+use crate::devices::LocatedDevice;
+use crate::error::HubError;
+use crate::groups;
+use crate::registry::DeviceRegistry;
+use crate::shadow::DeviceShadow;
+use crate::shutdown::Shutdown;
 use crate::thread_sharing::*;
 
-const KITCHEN_UUID: Uuid = Uuid::from_u128(0x36bc0fe1b00742809ec6b36c8bc98537);
-const BEDROOM_UUID: Uuid = Uuid::from_u128(0x0584507902e74f44b67902b90775abda);
 const VOICE_UUID: Uuid = Uuid::from_u128(0x7e1be1ebf9844e17b0f1049e02a39567);
 const SET_UUID: Uuid = Uuid::from_u128(0x2a4fae8107134e1fa8187ac56e4f13e4);
+const STATUS_UUID: Uuid = Uuid::from_u128(0x5c3a9e0d7f1b4c2e9a6d8b3f1e2c4a70);
 const _ON_UUID: Uuid = Uuid::from_u128(0x928e9b929939486b998d69613f89a9a6);
-#[allow(dead_code)]
-const MANUFACTURER_ID: u16 = 0x45F1;
 
-pub async fn run_ble_server(
-    shared_action: Arc<Mutex<SharedBLEAction>>,
-    devices: Vec<(String, Uuid)>,
-) {
-    let session = bluer::Session::new().await.unwrap();
-    let adapter = session.default_adapter().await.unwrap();
-    adapter.set_powered(true).await.unwrap();
+/// How long to wait before registering again after losing BlueZ or the adapter
+const REREGISTER_DELAY: Duration = Duration::from_secs(5);
 
-    println!(
-        "Advertising on Bluetooth adapter {} with address {}",
-        adapter.name(),
-        adapter.address().await.unwrap()
-    );
+/// Builds the advertisement for the configured name and manufacturer
+fn advertisement(ble_config: &BleConfig) -> Advertisement {
     let mut manufacturer_data = BTreeMap::new();
-    manufacturer_data.insert(MANUFACTURER_ID, vec![0x21, 0x22, 0x23, 0x24]);
-    let le_advertisement = Advertisement {
-        service_uuids: vec![BEDROOM_UUID].into_iter().collect(),
+    manufacturer_data.insert(ble_config.manufacturer_id, vec![0x21, 0x22, 0x23, 0x24]);
+    Advertisement {
+        service_uuids: vec![VOICE_UUID].into_iter().collect(),
         manufacturer_data: manufacturer_data.clone(),
         discoverable: Some(true),
-        local_name: Some("VanColleague".to_string()),
+        local_name: Some(ble_config.local_name.clone()),
         ..Default::default()
+    }
+}
+
+/// Everything the Bluetooth services need to get at
+#[derive(Debug, Clone)]
+pub struct BleContext {
+    pub shared_config: Arc<Mutex<SharedConfig>>,
+    pub command_bus: CommandBus,
+    pub registry: DeviceRegistry,
+    pub shadow: DeviceShadow,
+    pub events: HubEvents,
+}
+
+/// Keeps the GATT application and advertisement registered until the hub shuts down,
+/// registering them again whenever BlueZ restarts or the adapter is power-cycled
+pub async fn run_ble_server(
+    context: BleContext,
+    mut config_changes: watch::Receiver<u64>,
+    shutdown: Shutdown,
+) {
+    let mut power_on = true;
+    loop {
+        match serve(&context, &mut config_changes, &shutdown, power_on).await {
+            Ok(()) => break,
+            Err(e) => {
+                eprintln!(
+                    "Bluetooth server stopped: {}, setting it up again in {} seconds",
+                    e,
+                    REREGISTER_DELAY.as_secs()
+                );
+                // Whoever turned the adapter off gets to decide when it comes back, after
+                // anything else it's powered on again
+                power_on = !matches!(e, ServeError::PoweredOff(_));
+            }
+        }
+        tokio::select! {
+            _ = sleep(REREGISTER_DELAY) => {}
+            _ = shutdown.triggered() => break,
+        }
+    }
+}
+
+/// Why the Bluetooth server stopped serving
+#[derive(Debug)]
+enum ServeError {
+    /// Someone powered off the named adapter
+    PoweredOff(String),
+    /// BlueZ or the adapter went away, or registering failed
+    Failed(String),
+}
+
+impl From<String> for ServeError {
+    fn from(reason: String) -> ServeError {
+        ServeError::Failed(reason)
+    }
+}
+
+impl fmt::Display for ServeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServeError::PoweredOff(adapter) => write!(f, "Adapter {} was powered off", adapter),
+            ServeError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// Registers everything with a fresh BlueZ session and serves it, returning Ok once the
+/// hub shuts down or why the registration was lost
+///
+/// - 'power_on': power the adapter on rather than waiting for someone else to
+async fn serve(
+    context: &BleContext,
+    config_changes: &mut watch::Receiver<u64>,
+    shutdown: &Shutdown,
+    power_on: bool,
+) -> Result<(), ServeError> {
+    let shared_config = &context.shared_config;
+    let session = match bluer::Session::new().await {
+        Ok(session) => session,
+        Err(e) => return Err(format!("Couldn't connect to BlueZ: {}", e).into()),
+    };
+    let adapter = match session.default_adapter().await {
+        Ok(adapter) => adapter,
+        Err(e) => return Err(format!("Couldn't get the Bluetooth adapter: {}", e).into()),
+    };
+    // BlueZ going away takes the adapter with it, so that's all there is to watch for
+    let mut session_events = match session.events().await {
+        Ok(events) => Box::pin(events),
+        Err(e) => return Err(format!("Couldn't watch BlueZ: {}", e).into()),
+    };
+    let mut adapter_events = match adapter.events().await {
+        Ok(events) => Box::pin(events),
+        Err(e) => return Err(format!("Couldn't watch adapter {}: {}", adapter.name(), e).into()),
+    };
+
+    if power_on {
+        if let Err(e) = adapter.set_powered(true).await {
+            return Err(format!("Couldn't power on adapter {}: {}", adapter.name(), e).into());
+        }
+    } else if !adapter.is_powered().await.unwrap_or(false) {
+        println!("Waiting for adapter {} to be powered on", adapter.name());
+        loop {
+            tokio::select! {
+                event = adapter_events.next() => match event {
+                    Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(true))) => break,
+                    Some(_) => {}
+                    None => return Err(format!("Lost adapter {}", adapter.name()).into()),
+                },
+                _ = shutdown.triggered() => return Ok(()),
+            }
+        }
+    }
+
+    let mut ble_config = shared_config.lock().await.hub.ble.clone();
+    match adapter.address().await {
+        Ok(address) => println!(
+            "Advertising on Bluetooth adapter {} with address {}",
+            adapter.name(),
+            address
+        ),
+        Err(_) => println!("Advertising on Bluetooth adapter {}", adapter.name()),
+    }
+    let mut adv_handle = match adapter.advertise(advertisement(&ble_config)).await {
+        Ok(handle) => Some(handle),
+        Err(e) => return Err(format!("Couldn't advertise: {}", e).into()),
     };
-    let adv_handle = adapter.advertise(le_advertisement).await.unwrap();
 
     println!(
         "Serving GATT service on Bluetooth adapter {}",
         adapter.name()
     );
-    let shared_kitchen_set_read = shared_action.clone();
-    let shared_kitchen_set_write = shared_action.clone();
-    let shared_kitchen_set_notify = shared_action.clone();
-    let shared_voice_set_write = shared_action.clone();
-    let shared_bedroom_set_read = shared_action.clone();
-    let shared_bedroom_set_write = shared_action.clone();
-    let value = Arc::new(Mutex::new(vec![0x10, 0x01, 0x01, 0x10]));
-    let value_notify = value.clone();
-    let value_notify2 = value.clone();
-    let value_read2 = value.clone();
-    let value_write2 = value.clone();
-    let app = Application {
-        services: vec![
-            Service {
-                uuid: BEDROOM_UUID,
-                primary: true,
-                characteristics: vec![Characteristic {
-                    uuid: SET_UUID,
-                    read: Some(CharacteristicRead {
-                        read: true,
-                        fun: Box::new(move |req| {
-                            let value = value_read2.clone();
-                            let shared_action_clone = shared_bedroom_set_read.clone();
-                            async move {
-                                {
-                                    let mut shared_action_guard = shared_action_clone.lock().await;
-                                    *shared_action_guard = SharedBLEAction::TargetInquiry {
-                                        device_uuid: BEDROOM_UUID,
-                                    };
-                                }
-                                let response =
-                                    await_for_inquiry_response(shared_action_clone.clone()).await;
-                                println!("BLE response: {}", &response);
-                                Ok(response.to_string().as_bytes().to_vec())
-                            }
-                            .boxed()
-                        }),
-                        ..Default::default()
-                    }),
-                    write: Some(CharacteristicWrite {
-                        write: true,
-                        write_without_response: true,
-                        method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
-                            let shared_action_clone = shared_bedroom_set_write.clone();
-                            async move {
-                                let text = std::str::from_utf8(&new_value).unwrap();
-                                let target: usize =
-                                    text.chars().take(1).collect::<String>().parse().unwrap();
-                                {
-                                    let mut shared_action_guard = shared_action_clone.lock().await;
-                                    *shared_action_guard = SharedBLEAction::Command {
-                                        device_uuid: BEDROOM_UUID,
-                                        action: Action::Set { target: target },
-                                    };
-                                }
-                                Ok(())
-                            }
-                            .boxed()
-                        })),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }],
-                ..Default::default()
+    // Subscribe first so no device coming or going is missed while registering
+    let mut device_events = context.events.subscribe();
+    let mut layout = gatt_layout(&context.registry, shared_config).await;
+    let app = application(&layout, context);
+    let mut app_handle = match adapter.serve_gatt_application(app).await {
+        Ok(handle) => Some(handle),
+        Err(e) => return Err(format!("Couldn't serve the GATT application: {}", e).into()),
+    };
+
+    println!("Service ready");
+    let mut watching_config = true;
+    let outcome = loop {
+        tokio::select! {
+            _ = shutdown.triggered() => break Ok(()),
+            event = session_events.next() => match event {
+                Some(SessionEvent::AdapterRemoved(name)) if name == adapter.name() => {
+                    break Err(format!("Adapter {} went away", name).into());
+                }
+                Some(_) => {}
+                None => break Err("Lost the connection to BlueZ".to_string().into()),
             },
-            Service {
-                uuid: KITCHEN_UUID,
-                primary: true,
-                characteristics: vec![Characteristic {
-                    uuid: SET_UUID,
-                    read: Some(CharacteristicRead {
-                        read: true,
-                        fun: Box::new(move |req| {
-                            dbg!(&req); // todo: does req have the uuid to look up the device?
-                            let shared_action_clone = shared_kitchen_set_read.clone();
-                            async move {
-                                {
-                                    let mut shared_action_guard = shared_action_clone.lock().await;
-                                    *shared_action_guard = SharedBLEAction::TargetInquiry {
-                                        device_uuid: KITCHEN_UUID,
-                                    };
-                                }
-                                let response =
-                                    await_for_inquiry_response(shared_action_clone.clone()).await;
-                                println!("BLE response: {}", &response);
-                                Ok(response.to_string().as_bytes().to_vec())
-                            }
-                            .boxed()
-                        }),
-                        ..Default::default()
-                    }),
-                    write: Some(CharacteristicWrite {
-                        write: true,
-                        write_without_response: true,
-                        method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, _req| {
-                            let shared_action_clone = shared_kitchen_set_write.clone();
-                            async move {
-                                let text = std::str::from_utf8(&new_value).unwrap();
-                                let target: usize =
-                                    text.chars().take(1).collect::<String>().parse().unwrap();
-                                {
-                                    let mut shared_action_guard = shared_action_clone.lock().await;
-                                    *shared_action_guard = SharedBLEAction::Command {
-                                        device_uuid: KITCHEN_UUID,
-                                        action: Action::Set { target: target },
-                                    };
-                                }
-                                Ok(())
-                            }
-                            .boxed()
-                        })),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }],
-                ..Default::default()
+            event = adapter_events.next() => match event {
+                Some(AdapterEvent::PropertyChanged(AdapterProperty::Powered(false))) => {
+                    break Err(ServeError::PoweredOff(adapter.name().to_string()));
+                }
+                Some(_) => {}
+                None => break Err(format!("Lost adapter {}", adapter.name()).into()),
             },
-            Service {
-                uuid: VOICE_UUID,
-                primary: true,
-                characteristics: vec![Characteristic {
-                    uuid: SET_UUID,
-                    write: Some(CharacteristicWrite {
-                        write: true,
-                        write_without_response: true,
-                        method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
-                            println!("voice recieved");
-                            let shared_action_clone = shared_voice_set_write.clone();
-                            let devices_clone = devices.clone();
-                            async move {
-                                let command = std::str::from_utf8(&new_value).unwrap();
-                                let command = command.to_lowercase();
-                                let command = command.trim_end();
-                                let command = command.trim_end_matches('\0');
-                                let command = command.split_whitespace().collect::<Vec<&str>>();
-                                let mut command = command.iter();
-                                let mut device = String::new();
+            event = device_events.recv() => {
+                // Only devices coming and going change the services
+                if let Ok(HubEvent::DeviceState { .. })
+                | Ok(HubEvent::DeviceMoved { .. })
+                | Ok(HubEvent::DeviceDegraded { .. }) = event
+                {
+                    continue;
+                }
+                if let Err(e) =
+                    refresh_application(&adapter, &mut app_handle, &mut layout, context).await
+                {
+                    break Err(e.into());
+                }
+            }
+            changed = config_changes.changed(), if watching_config => {
+                if changed.is_err() {
+                    // Nobody can reload anymore
+                    watching_config = false;
+                    continue;
+                }
+                // Advertise again if the name or manufacturer changed
+                let new_config = shared_config.lock().await.hub.ble.clone();
+                if new_config != ble_config {
+                    drop(adv_handle.take());
+                    match adapter.advertise(advertisement(&new_config)).await {
+                        Ok(handle) => {
+                            println!("Now advertising as {}", &new_config.local_name);
+                            adv_handle = Some(handle);
+                        }
+                        Err(e) => eprintln!("Failed to advertise the new settings: {}", e),
+                    }
+                    ble_config = new_config;
+                }
+                // Rooms may have changed
+                if let Err(e) =
+                    refresh_application(&adapter, &mut app_handle, &mut layout, context).await
+                {
+                    break Err(e.into());
+                }
+            }
+        }
+    };
 
-                                while !devices_clone
-                                    .clone()
-                                    .iter()
-                                    .map(|(n, _)| n)
-                                    .collect::<Vec<&String>>()
-                                    .contains(&&device)
-                                {
-                                    let word = match command.next() {
-                                        Some(w) => w,
-                                        None => {
-                                            panic!("Didn't get the device name");
-                                        }
-                                    };
+    println!("Removing service and advertisement");
+    drop(app_handle);
+    drop(adv_handle);
+    if outcome.is_ok() {
+        sleep(Duration::from_secs(1)).await;
+    }
+    outcome
+}
 
-                                    if device.is_empty() {
-                                        device = word.to_string();
-                                    } else {
-                                        device = format!("{} {}", &device, &word);
-                                    }
-                                }
-                                let mut uuid = Uuid::from_u128(0x0);
-                                for (n, u) in devices_clone.iter() {
-                                    if &device == n {
-                                        uuid = u.clone();
-                                        break;
-                                    }
-                                }
-                                if uuid.as_u128() == 0x0 {
-                                    panic!("didn't get the device id");
-                                }
+/// The services as they stand, the uuid of every device and group and whether it's a group
+type GattLayout = Vec<(Uuid, bool)>;
 
-                                let action = match command.next() {
-                                    Some(&"at") => "set",
-                                    Some(a) => a,
-                                    None => panic!("failed to get an action"), //return HttpResponse::Ok().body("Oops, we didn't get an action!"),
-                                };
-                                let target = match command.next() {
-                                    Some(t) => {
-                                        if t.is_empty() {
-                                            None
-                                        } else {
-                                            let t: usize = match t {
-                                                &"zero" => 0,
-                                                &"one" => 1,
-                                                &"1:00" => 1,
-                                                &"two" => 2,
-                                                &"too" => 2,
-                                                &"to" => 2,
-                                                &"2:00" => 2,
-                                                &"three" => 3,
-                                                &"3:00" => 3,
-                                                &"four" => 4,
-                                                &"4:00" => 4,
-                                                &"for" => 4,
-                                                &"five" => 5,
-                                                &"5:00" => 5,
-                                                &"six" => 6,
-                                                &"6:00" => 6,
-                                                &"seven" => 7,
-                                                &"7:00" => 7,
-                                                _ => panic!("Bad target spoken"),
-                                            };
-                                            Some(t)
-                                            /*match t.parse::<usize>() {
-                                                Ok(n) => {
-                                                    if n < 8 {
-                                                        Some(n)
-                                                    } else {
-                                                        panic!("Target's too high");
-                                                    }
-                                                }
-                                                Err(_) => {
-                                                    panic!("parse issue");
-                                                }
-                                            }*/
-                                        }
-                                    }
-                                    None => None,
-                                };
+/// A service for every online device plus one for every group with an online member
+async fn gatt_layout(
+    registry: &DeviceRegistry,
+    shared_config: &Arc<Mutex<SharedConfig>>,
+) -> GattLayout {
+    let online: Vec<LocatedDevice> = registry
+        .snapshot()
+        .await
+        .into_values()
+        .filter(|ld| ld.online)
+        .collect();
+    let mut layout: GattLayout = online.iter().map(|ld| (ld.device.uuid, false)).collect();
+    for group in groups::groups(&shared_config.lock().await.hub) {
+        if online.iter().any(|ld| group.contains(ld)) {
+            layout.push((group.uuid, true));
+        }
+    }
+    layout.sort_by_key(|(uuid, _)| *uuid);
+    layout
+}
 
-                                let action = match Action::from_str(action, target) {
-                                    Ok(a) => a,
-                                    Err(_) => {
-                                        panic!("Issue creating the action");
-                                    }
-                                };
+/// Serves the services again if the layout has changed since they were last served
+async fn refresh_application(
+    adapter: &Adapter,
+    app_handle: &mut Option<ApplicationHandle>,
+    layout: &mut GattLayout,
+    context: &BleContext,
+) -> Result<(), String> {
+    let new_layout = gatt_layout(&context.registry, &context.shared_config).await;
+    if new_layout == *layout {
+        return Ok(());
+    }
+    // The old services go first so phones never see the same one twice
+    drop(app_handle.take());
+    let app = application(&new_layout, context);
+    match adapter.serve_gatt_application(app).await {
+        Ok(handle) => {
+            println!("Now serving {} device and group services", new_layout.len());
+            *app_handle = Some(handle);
+            *layout = new_layout;
+            Ok(())
+        }
+        Err(e) => Err(format!("Couldn't serve the new GATT application: {}", e)),
+    }
+}
 
-                                {
-                                    let mut shared_action_guard = shared_action_clone.lock().await;
-                                    *shared_action_guard = SharedBLEAction::Command {
-                                        device_uuid: uuid,
-                                        action: action,
-                                    };
-                                }
-                                Ok(())
-                            }
-                            .boxed()
-                        })),
-                        ..Default::default()
-                    }),
-                    ..Default::default()
-                }],
-                ..Default::default()
-            },
-        ],
+/// Builds the GATT application for the layout, along with the voice service
+fn application(layout: &GattLayout, context: &BleContext) -> Application {
+    let mut services = vec![voice_service(context)];
+    for (uuid, is_group) in layout.iter() {
+        let (statuses, _) = watch::channel(None);
+        let statuses = Arc::new(statuses);
+        services.push(Service {
+            uuid: *uuid,
+            primary: true,
+            characteristics: vec![
+                set_characteristic(*uuid, context, &statuses, !is_group),
+                status_characteristic(&statuses),
+            ],
+            ..Default::default()
+        });
+    }
+    Application {
+        services,
         ..Default::default()
-    };
+    }
+}
 
-    let app_handle = adapter.serve_gatt_application(app).await.unwrap();
+/// The characteristic every device and group service has, writing a command carries it
+/// out, while reading gets the device's current target and subscribers are sent every
+/// new one
+///
+/// Commands are in either of the `ble_protocol` formats, the binary ones have their
+/// status put on `statuses`.
+///
+/// - 'single_device': groups don't have a single target to read or send
+fn set_characteristic(
+    device_uuid: Uuid,
+    context: &BleContext,
+    statuses: &Arc<watch::Sender<Option<CommandStatus>>>,
+    single_device: bool,
+) -> Characteristic {
+    let set_read_bus = context.command_bus.clone();
+    let set_read_shadow = context.shadow.clone();
+    let set_write_bus = context.command_bus.clone();
+    let set_write_statuses = statuses.clone();
+    let set_notify_events = context.events.clone();
+    let read = match single_device {
+        true => Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |_req| {
+                let command_bus = set_read_bus.clone();
+                let shadow = set_read_shadow.clone();
+                async move {
+                    let response = inquire_target(&shadow, &command_bus, device_uuid).await?;
+                    println!("BLE response: {}", &response);
+                    Ok(response.to_string().as_bytes().to_vec())
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        false => None,
+    };
+    let notify = match single_device {
+        true => Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Fun(Box::new(move |notifier| {
+                let device_events = set_notify_events.subscribe();
+                async move {
+                    tokio::spawn(notify_state_changes(device_uuid, notifier, device_events));
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        false => None,
+    };
+    Characteristic {
+        uuid: SET_UUID,
+        read,
+        write: Some(CharacteristicWrite {
+            write: true,
+            write_without_response: true,
+            method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, _req| {
+                let command_bus = set_write_bus.clone();
+                let statuses = set_write_statuses.clone();
+                async move {
+                    match ble_protocol::decode(&new_value) {
+                        Ok(BleWrite::Legacy(action)) => {
+                            let request = HubRequest::Command {
+                                device_uuid,
+                                action,
+                            };
+                            send_command(&command_bus, request).await
+                        }
+                        Ok(BleWrite::Binary(command)) => {
+                            let result = submit_command(
+                                &command_bus,
+                                HubRequest::Command {
+                                    device_uuid,
+                                    action: command.action,
+                                },
+                            )
+                            .await;
+                            let code = match &result {
+                                Ok(()) => StatusCode::Ok,
+                                Err(e) => StatusCode::from(e),
+                            };
+                            statuses.send_replace(Some(CommandStatus {
+                                request_id: command.request_id,
+                                code,
+                            }));
+                            result.map_err(|_| ReqError::Failed)
+                        }
+                        Err(e) => {
+                            eprintln!("Couldn't understand the write for {}: {}", &device_uuid, e);
+                            if let Some(request_id) = ble_protocol::request_id(&new_value) {
+                                statuses.send_replace(Some(CommandStatus {
+                                    request_id,
+                                    code: e.status_code(),
+                                }));
+                            }
+                            Err(ReqError::NotSupported)
+                        }
+                    }
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        notify,
+        ..Default::default()
+    }
+}
 
-    println!("Service ready. Press enter to quit.");
-    let stdin = BufReader::new(tokio::io::stdin());
-    let mut lines = stdin.lines();
-    let _ = lines.next_line().await;
+/// Holds the status of the last binary command written to the service, which
+/// subscribers are sent as each one comes in
+fn status_characteristic(statuses: &Arc<watch::Sender<Option<CommandStatus>>>) -> Characteristic {
+    let status_read_statuses = statuses.clone();
+    let status_notify_statuses = statuses.clone();
+    Characteristic {
+        uuid: STATUS_UUID,
+        read: Some(CharacteristicRead {
+            read: true,
+            fun: Box::new(move |_req| {
+                let status = *status_read_statuses.borrow();
+                async move {
+                    match status {
+                        Some(status) => Ok(status.encode()),
+                        None => Ok(Vec::new()),
+                    }
+                }
+                .boxed()
+            }),
+            ..Default::default()
+        }),
+        notify: Some(CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Fun(Box::new(move |notifier| {
+                let statuses = status_notify_statuses.subscribe();
+                async move {
+                    tokio::spawn(notify_statuses(notifier, statuses));
+                }
+                .boxed()
+            })),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
 
-    println!("Removing service and advertisement");
-    drop(app_handle);
-    drop(adv_handle);
-    sleep(Duration::from_secs(1)).await;
+/// Sends a subscriber every status as it comes in, until they unsubscribe
+async fn notify_statuses(
+    mut notifier: CharacteristicNotifier,
+    mut statuses: watch::Receiver<Option<CommandStatus>>,
+) {
+    while statuses.changed().await.is_ok() {
+        let status = *statuses.borrow_and_update();
+        let status = match status {
+            Some(status) => status,
+            None => continue,
+        };
+        if notifier.is_stopped() {
+            break;
+        }
+        if let Err(e) = notifier.notify(status.encode()).await {
+            eprintln!("Stopped notifying about statuses: {}", e);
+            break;
+        }
+    }
 }
 
-async fn await_for_inquiry_response(shared_action: Arc<Mutex<SharedBLEAction>>) -> usize {
-    println!("Waiting???????????");
+/// Sends a subscriber the device's target every time it changes, in the same form a
+/// read gets it, until they unsubscribe
+async fn notify_state_changes(
+    device_uuid: Uuid,
+    mut notifier: CharacteristicNotifier,
+    mut device_events: broadcast::Receiver<HubEvent>,
+) {
     loop {
+        let device = match device_events.recv().await {
+            Ok(HubEvent::DeviceState { device }) if device.uuid == device_uuid => device,
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if notifier.is_stopped() {
+            break;
+        }
+        if let Err(e) = notifier
+            .notify(device.target.to_string().into_bytes())
+            .await
         {
-            let mut lock = shared_action.lock().await;
-            match &*lock {
-                SharedBLEAction::TargetResponse { ref target } => {
-                    let thing = target.clone();
-                    *lock = SharedBLEAction::NoUpdate;
-                    return thing;
+            eprintln!("Stopped notifying about {}: {}", &device_uuid, e);
+            break;
+        }
+    }
+}
+
+/// Takes spoken commands, e.g. "kitchen light set three"
+fn voice_service(context: &BleContext) -> Service {
+    let registry = context.registry.clone();
+    let voice_set_write_bus = context.command_bus.clone();
+    let voice_set_write_config = context.shared_config.clone();
+    Service {
+        uuid: VOICE_UUID,
+        primary: true,
+        characteristics: vec![Characteristic {
+            uuid: SET_UUID,
+            write: Some(CharacteristicWrite {
+                write: true,
+                write_without_response: true,
+                method: CharacteristicWriteMethod::Fun(Box::new(move |new_value, req| {
+                    println!("voice recieved");
+                    let command_bus = voice_set_write_bus.clone();
+                    let registry = registry.clone();
+                    let shared_config = voice_set_write_config.clone();
+                    async move {
+                        let names = groups::command_names(&registry, &shared_config).await;
+                        let matching = shared_config.lock().await.hub.matching;
+                        let command = match std::str::from_utf8(&new_value) {
+                            Ok(command) => command,
+                            Err(_) => {
+                                return Err(att_error(&HubError::invalid_command(
+                                    "The command isn't valid UTF-8",
+                                )))
+                            }
+                        };
+                        let parsed = match command_parser::parse(command, &names, &matching) {
+                            Ok(parsed) => parsed,
+                            Err(e) => {
+                                eprintln!("Couldn't parse the voice command '{}': {}", command, e);
+                                return Err(att_error(&e.into()));
+                            }
+                        };
+
+                        println!("Voice command for {}", parsed.name);
+                        send_command(&command_bus, parsed.request()).await
+                    }
+                    .boxed()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+/// The device's target as the shadow has it, only asking the business logic when the
+/// hub hasn't heard it yet
+async fn inquire_target(
+    shadow: &DeviceShadow,
+    command_bus: &CommandBus,
+    device_uuid: Uuid,
+) -> Result<usize, ReqError> {
+    if let Some(state) = shadow.get(&device_uuid).await {
+        return Ok(state.device.target);
+    }
+    match command_bus
+        .submit(HubRequest::TargetInquiry { device_uuid })
+        .await
+    {
+        Ok(HubResponse::Target { target }) => Ok(target),
+        Ok(HubResponse::Device { device }) => Ok(device.target),
+        Ok(_) => Err(ReqError::Failed),
+        Err(e) => {
+            eprintln!("Target inquiry for {} failed: {}", &device_uuid, e);
+            Err(att_error(&e))
+        }
+    }
+}
+
+/// Sends the command along to the business logic, failing the write if it didn't go through
+async fn send_command(command_bus: &CommandBus, request: HubRequest) -> Result<(), ReqError> {
+    submit_command(command_bus, request)
+        .await
+        .map_err(|e| att_error(&e))
+}
+
+/// The ATT error a client gets for a failed request, not supported when the request
+/// itself was bad and a plain failure otherwise
+fn att_error(error: &HubError) -> ReqError {
+    if error.is_bad_request() {
+        ReqError::NotSupported
+    } else {
+        ReqError::Failed
+    }
+}
+
+/// Sends the command along to the business logic, returning what went wrong if it
+/// didn't go through, for a group the first member it didn't go through for
+async fn submit_command(command_bus: &CommandBus, request: HubRequest) -> Result<(), HubError> {
+    match command_bus.submit(request).await {
+        Ok(HubResponse::Group { results }) => {
+            let mut failure = None;
+            for result in results {
+                if let Some(e) = result.error {
+                    eprintln!("Command for {} failed: {}", &result.name, e);
+                    failure.get_or_insert(e);
                 }
-                _ => {}
             }
+            match failure {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("{:?} failed: {}", request, e);
+            Err(e)
         }
     }
 }
@@ -0,0 +1,703 @@
+//! Turns spoken or typed commands like "living room lamp at three" into an action for a
+//! device, the same way for HTTP, BLE voice writes and the control socket.
+//!
+//! The grammar is `<name> <verb> [target]`:
+//!
+//! - the name is any number of words, the longest run that names a device, an alias or
+//!   a group wins. When no run is exactly a name, the name most like one of the runs
+//!   is taken instead, so "kitchen lights" and "bed room light" still find "kitchen
+//!   light" and "bedroom light", see `MatchingConfig`
+//! - the verb is `on`, `off`, `set` or `at`, `at` being what "set the lamp at 3" comes out
+//!   of speech recognition as, or one that moves the target up or down from wherever
+//!   it is, like `brighter`, `dimmer`, `up` or `down`
+//! - `set`/`at` take a target, as a digit, a number word, or the "3:00" that speech
+//!   recognition likes to hear numbers as, or a percentage like "40 percent" or "40%".
+//!   The relative verbs can take how many steps to go, e.g. "up two" or "down by 3"
+//!
+//! Relative verbs depend on the device's current target and percentages on its range,
+//! so they come out as an `Adjustment` for the business logic to work out.
+use std::fmt;
+
+use bluer::Uuid;
+use device::Action;
+use serde::{Deserialize, Serialize};
+
+use crate::config::MatchingConfig;
+use crate::devices::TargetRange;
+use crate::error::HubError;
+use crate::thread_sharing::HubRequest;
+
+/// Words speech recognition hears numbers as, including the ones that sound alike
+const NUMBER_WORDS: [(&str, usize); 26] = [
+    ("zero", 0),
+    ("one", 1),
+    ("won", 1),
+    ("two", 2),
+    ("too", 2),
+    ("to", 2),
+    ("three", 3),
+    ("four", 4),
+    ("for", 4),
+    ("five", 5),
+    ("six", 6),
+    ("seven", 7),
+    ("eight", 8),
+    ("ate", 8),
+    ("nine", 9),
+    ("ten", 10),
+    ("eleven", 11),
+    ("twelve", 12),
+    ("thirteen", 13),
+    ("fourteen", 14),
+    ("fifteen", 15),
+    ("sixteen", 16),
+    ("seventeen", 17),
+    ("eighteen", 18),
+    ("nineteen", 19),
+    ("twenty", 20),
+];
+
+/// The verbs that move the target from where it is, and which way
+const RELATIVE_VERBS: [(&str, i32); 10] = [
+    ("up", 1),
+    ("brighter", 1),
+    ("brighten", 1),
+    ("higher", 1),
+    ("faster", 1),
+    ("down", -1),
+    ("dimmer", -1),
+    ("dim", -1),
+    ("lower", -1),
+    ("slower", -1),
+];
+
+/// A change to a device's target relative to where it is now or to its range
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Adjustment {
+    /// Up, or down when negative, this many steps
+    By(i32),
+    /// This share of the way from the lowest target to the highest
+    Percent(u8),
+}
+
+impl Adjustment {
+    /// The target this makes of `current`, kept within the device's range
+    pub fn apply(&self, current: usize, range: &TargetRange) -> usize {
+        match *self {
+            Adjustment::By(steps) => {
+                let current = i64::try_from(current).unwrap_or(i64::MAX);
+                range.clamp(current.saturating_add(steps as i64))
+            }
+            Adjustment::Percent(_) => self.absolute(range).unwrap_or(range.min),
+        }
+    }
+
+    /// The target this makes from the range alone, None when it depends on the
+    /// device's current target
+    pub fn absolute(&self, range: &TargetRange) -> Option<usize> {
+        match *self {
+            Adjustment::By(_) => None,
+            Adjustment::Percent(percent) => {
+                let span = range.max.saturating_sub(range.min);
+                let share = span
+                    .saturating_mul(percent.min(100) as usize)
+                    .saturating_add(50);
+                Some(range.min.saturating_add(share / 100))
+            }
+        }
+    }
+}
+
+/// What a command asks to happen to the device
+#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
+pub enum Change {
+    Action(Action),
+    Adjust(Adjustment),
+}
+
+/// A command the parser understood
+#[derive(Debug, Clone)]
+pub struct ParsedCommand {
+    /// The name as it was matched, lowercase
+    pub name: String,
+    pub uuid: Uuid,
+    pub change: Change,
+}
+
+impl ParsedCommand {
+    /// The request that carries the command out
+    pub fn request(&self) -> HubRequest {
+        match self.change {
+            Change::Action(action) => HubRequest::Command {
+                device_uuid: self.uuid,
+                action,
+            },
+            Change::Adjust(adjustment) => HubRequest::Adjust {
+                device_uuid: self.uuid,
+                adjustment,
+            },
+        }
+    }
+}
+
+/// Why a command couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    /// None of the leading words name anything
+    UnknownName(String),
+    /// The leading words are about as close to several names
+    AmbiguousName {
+        name: String,
+        candidates: Vec<String>,
+    },
+    MissingAction,
+    UnknownAction(String),
+    MissingTarget,
+    BadTarget(String),
+    PercentOutOfRange(usize),
+    /// "Up by zero" and the like
+    NoSteps,
+    /// Words left over after a complete command
+    UnexpectedWords(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseError::*;
+        match self {
+            Empty => write!(f, "The command is empty"),
+            UnknownName(words) => write!(f, "Nothing is named in '{}'", words),
+            AmbiguousName { name, candidates } => {
+                write!(f, "'{}' could be any of {}", name, candidates.join(", "))
+            }
+            MissingAction => write!(f, "Expected on, off, set, at, up or down after the name"),
+            UnknownAction(verb) => write!(f, "'{}' isn't on, off, set, at, up or down", verb),
+            MissingTarget => write!(f, "Expected a target to set"),
+            BadTarget(target) => write!(f, "'{}' isn't a target", target),
+            NoSteps => write!(f, "Going up or down takes at least one step"),
+            PercentOutOfRange(percent) => {
+                write!(
+                    f,
+                    "{} percent is out of range, it should be 0 though 100",
+                    percent
+                )
+            }
+            UnexpectedWords(words) => write!(f, "Didn't expect '{}' at the end", words),
+        }
+    }
+}
+
+impl From<ParseError> for HubError {
+    fn from(error: ParseError) -> HubError {
+        match error {
+            ParseError::UnknownName(name) => HubError::UnknownName { name },
+            ParseError::AmbiguousName { name, candidates } => {
+                HubError::AmbiguousName { name, candidates }
+            }
+            other => HubError::invalid_command(other.to_string()),
+        }
+    }
+}
+
+/// Parses a whole command, matching the name against `names`
+///
+/// Case, surrounding whitespace and the trailing NULs BLE writes can carry are ignored.
+pub fn parse(
+    command: &str,
+    names: &[(String, Uuid)],
+    matching: &MatchingConfig,
+) -> Result<ParsedCommand, ParseError> {
+    let command = command.trim_end_matches('\0').to_lowercase();
+    let words: Vec<&str> = command.split_whitespace().collect();
+    parse_words(&words, names, matching)
+}
+
+/// Parses a command that's already been split into words
+pub fn parse_words(
+    words: &[&str],
+    names: &[(String, Uuid)],
+    matching: &MatchingConfig,
+) -> Result<ParsedCommand, ParseError> {
+    if words.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let (name, uuid, rest) = match_name(words, names, matching)?;
+    let change = parse_action(rest)?;
+    Ok(ParsedCommand { name, uuid, change })
+}
+
+/// Finds what a name on its own refers to, the same way as the name in a command
+pub fn find_name(
+    name: &str,
+    names: &[(String, Uuid)],
+    matching: &MatchingConfig,
+) -> Result<(String, Uuid), ParseError> {
+    let name = name.to_lowercase();
+    let words: Vec<&str> = name.split_whitespace().collect();
+    if words.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    match match_name(&words, names, matching)? {
+        (matched, uuid, []) => Ok((matched, uuid)),
+        _ => Err(ParseError::UnknownName(words.join(" "))),
+    }
+}
+
+/// Takes the longest run of leading words that's one of the names, or failing that
+/// the closest name if fuzzy matching is on
+fn match_name<'a, 'w>(
+    words: &'a [&'w str],
+    names: &[(String, Uuid)],
+    matching: &MatchingConfig,
+) -> Result<(String, Uuid, &'a [&'w str]), ParseError> {
+    for name_len in (1..=words.len()).rev() {
+        let name = words[..name_len].join(" ").to_lowercase();
+        if let Some((_, uuid)) = names.iter().find(|(n, _)| n.to_lowercase() == name) {
+            return Ok((name, *uuid, &words[name_len..]));
+        }
+    }
+    if matching.fuzzy {
+        return fuzzy_match_name(words, names, matching);
+    }
+    Err(ParseError::UnknownName(words.join(" ")))
+}
+
+/// The best match found for one uuid
+struct Candidate<'n> {
+    similarity: f64,
+    name: &'n str,
+    uuid: Uuid,
+    /// How many of the leading words it matched
+    name_len: usize,
+}
+
+/// Compares every run of leading words with every name, taking the most similar name
+/// as long as no other device's name is about as similar
+///
+/// Aliases point at the same uuid as the name, so a device can't be ambiguous with itself.
+fn fuzzy_match_name<'a, 'w>(
+    words: &'a [&'w str],
+    names: &[(String, Uuid)],
+    matching: &MatchingConfig,
+) -> Result<(String, Uuid, &'a [&'w str]), ParseError> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for name_len in 1..=words.len() {
+        let heard = words[..name_len].join(" ");
+        for (name, uuid) in names {
+            let similarity = similarity(&heard, name);
+            if similarity < matching.threshold {
+                continue;
+            }
+            let candidate = Candidate {
+                similarity,
+                name,
+                uuid: *uuid,
+                name_len,
+            };
+            match candidates.iter_mut().find(|c| c.uuid == *uuid) {
+                Some(best) => {
+                    if similarity > best.similarity {
+                        *best = candidate;
+                    }
+                }
+                None => candidates.push(candidate),
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    let best = match candidates.first() {
+        Some(best) => best,
+        None => return Err(ParseError::UnknownName(words.join(" "))),
+    };
+    let mut close: Vec<String> = candidates
+        .iter()
+        .filter(|c| best.similarity - c.similarity < matching.ambiguity_margin)
+        .map(|c| c.name.to_lowercase())
+        .collect();
+    if close.len() > 1 {
+        close.sort();
+        return Err(ParseError::AmbiguousName {
+            name: words[..best.name_len].join(" "),
+            candidates: close,
+        });
+    }
+    Ok((best.name.to_lowercase(), best.uuid, &words[best.name_len..]))
+}
+
+/// How alike two names are from 0 to 1, ignoring case and where the spaces are
+fn similarity(heard: &str, name: &str) -> f64 {
+    let name = name.to_lowercase();
+    let squashed = |s: &str| s.split_whitespace().collect::<String>();
+    strsim::normalized_levenshtein(heard, &name).max(strsim::normalized_levenshtein(
+        &squashed(heard),
+        &squashed(&name),
+    ))
+}
+
+/// Parses the `<verb> [target]` after the name
+fn parse_action(words: &[&str]) -> Result<Change, ParseError> {
+    let (verb, rest) = match words.split_first() {
+        Some((verb, rest)) => (verb.to_lowercase(), rest),
+        None => return Err(ParseError::MissingAction),
+    };
+    let (change, rest) = match verb.as_str() {
+        "on" => (Change::Action(Action::On), rest),
+        "off" => (Change::Action(Action::Off), rest),
+        "set" | "at" => parse_set(rest)?,
+        _ => match RELATIVE_VERBS.iter().find(|(v, _)| *v == verb) {
+            Some((_, direction)) => parse_steps(*direction, rest)?,
+            None => return Err(ParseError::UnknownAction(verb)),
+        },
+    };
+    if !rest.is_empty() {
+        return Err(ParseError::UnexpectedWords(rest.join(" ")));
+    }
+    Ok(change)
+}
+
+/// Parses what's set to, a target or a percentage like "40 percent" or "40%"
+fn parse_set<'a, 'w>(words: &'a [&'w str]) -> Result<(Change, &'a [&'w str]), ParseError> {
+    let (target, rest) = match words.split_first() {
+        Some(split) => split,
+        None => return Err(ParseError::MissingTarget),
+    };
+    let (percent, rest) = match (target.strip_suffix('%'), rest) {
+        (Some(percent), rest) => (Some(percent), rest),
+        (None, ["percent", rest @ ..]) | (None, ["per", "cent", rest @ ..]) => {
+            (Some(*target), rest)
+        }
+        (None, rest) => (None, rest),
+    };
+    let change = match percent {
+        Some(percent) => match parse_number(percent)? {
+            percent if percent > 100 => return Err(ParseError::PercentOutOfRange(percent)),
+            percent => Change::Adjust(Adjustment::Percent(percent as u8)),
+        },
+        None => Change::Action(Action::Set {
+            target: parse_number(target)?,
+        }),
+    };
+    Ok((change, rest))
+}
+
+/// Parses how many steps to go after "up", "dimmer" and the like, one unless a number
+/// follows, with an optional "by" in between
+fn parse_steps<'a, 'w>(
+    direction: i32,
+    words: &'a [&'w str],
+) -> Result<(Change, &'a [&'w str]), ParseError> {
+    let words = match words {
+        ["by", rest @ ..] => rest,
+        _ => words,
+    };
+    let (steps, rest) = match words.split_first() {
+        Some((steps, rest)) => (parse_number(steps)?, rest),
+        None => (1, words),
+    };
+    if steps == 0 {
+        return Err(ParseError::NoSteps);
+    }
+    let steps = direction * steps as i32;
+    Ok((Change::Adjust(Adjustment::By(steps)), rest))
+}
+
+/// Reads a number from a digit, a number word or an "N:00"
+///
+/// Whether the device takes it as a target is up to the device, see `TargetRange`.
+fn parse_number(word: &str) -> Result<usize, ParseError> {
+    let word = word.to_lowercase();
+    let number = word.strip_suffix(":00").unwrap_or(&word);
+    match number.parse::<usize>() {
+        Ok(number) => Ok(number),
+        Err(_) => match NUMBER_WORDS.iter().find(|(w, _)| *w == number) {
+            Some((_, number)) => Ok(*number),
+            None => Err(ParseError::BadTarget(word)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HubConfig;
+
+    fn names() -> Vec<(String, Uuid)> {
+        vec![
+            ("lamp".to_string(), Uuid::from_u128(1)),
+            ("living room lamp".to_string(), Uuid::from_u128(2)),
+            ("Bedroom Fan".to_string(), Uuid::from_u128(3)),
+            ("lights".to_string(), Uuid::from_u128(4)),
+            ("kitchen light".to_string(), Uuid::from_u128(5)),
+            ("galley light".to_string(), Uuid::from_u128(5)),
+            ("red light".to_string(), Uuid::from_u128(6)),
+            ("bed light".to_string(), Uuid::from_u128(7)),
+        ]
+    }
+
+    /// What a command should come out as, the uuid and action or the error
+    enum Expected {
+        On(u128),
+        Off(u128),
+        Set(u128, usize),
+        By(u128, i32),
+        Percent(u128, u8),
+        Fails(ParseError),
+    }
+
+    #[test]
+    fn parses_commands() {
+        use Expected::*;
+        let cases = [
+            ("lamp on", On(1)),
+            ("lamp off", Off(1)),
+            ("lamp set 3", Set(1, 3)),
+            ("lamp at 3", Set(1, 3)),
+            ("LAMP ON", On(1)),
+            ("  lamp   on  ", On(1)),
+            ("lamp on\0\0", On(1)),
+            ("living room lamp on", On(2)),
+            ("living room lamp at five", Set(2, 5)),
+            ("bedroom fan at 4:00", Set(3, 4)),
+            ("bedroom fan at for", Set(3, 4)),
+            ("bedroom fan at too", Set(3, 2)),
+            ("bedroom fan at to", Set(3, 2)),
+            ("lights at zero", Set(4, 0)),
+            ("lights at seven", Set(4, 7)),
+            ("lights at 7:00", Set(4, 7)),
+            ("lights at 0", Set(4, 0)),
+            ("kitchen lights on", On(5)),
+            ("kitchen night off", Off(5)),
+            ("galley lights at 3", Set(5, 3)),
+            ("bed room fan at 3", Set(3, 3)),
+            ("bedroom fans off", Off(3)),
+            (
+                "ted light on",
+                Fails(ParseError::AmbiguousName {
+                    name: "ted light".to_string(),
+                    candidates: vec!["bed light".to_string(), "red light".to_string()],
+                }),
+            ),
+            ("", Fails(ParseError::Empty)),
+            ("\0", Fails(ParseError::Empty)),
+            (
+                "kitchen on",
+                Fails(ParseError::UnknownName("kitchen on".to_string())),
+            ),
+            (
+                "living room on",
+                Fails(ParseError::UnknownName("living room on".to_string())),
+            ),
+            ("lamp brighter", By(1, 1)),
+            ("lamp dimmer", By(1, -1)),
+            ("lamp dim", By(1, -1)),
+            ("lamp up", By(1, 1)),
+            ("lamp up two", By(1, 2)),
+            ("lamp down by 3", By(1, -3)),
+            ("bedroom fan faster", By(3, 1)),
+            ("bedroom fan slower by too", By(3, -2)),
+            ("lights up", By(4, 1)),
+            ("kitchen light at 40 percent", Percent(5, 40)),
+            ("kitchen light at 40%", Percent(5, 40)),
+            ("kitchen light set 100 per cent", Percent(5, 100)),
+            ("kitchen light at zero percent", Percent(5, 0)),
+            ("lamp", Fails(ParseError::MissingAction)),
+            (
+                "lamp flicker",
+                Fails(ParseError::UnknownAction("flicker".to_string())),
+            ),
+            ("lamp up 0", Fails(ParseError::NoSteps)),
+            ("lamp down 8", By(1, -8)),
+            ("lamp up ten", By(1, 10)),
+            (
+                "lamp up lots",
+                Fails(ParseError::BadTarget("lots".to_string())),
+            ),
+            (
+                "lamp brighter please",
+                Fails(ParseError::BadTarget("please".to_string())),
+            ),
+            (
+                "lamp at 101 percent",
+                Fails(ParseError::PercentOutOfRange(101)),
+            ),
+            (
+                "lamp at 40% now",
+                Fails(ParseError::UnexpectedWords("now".to_string())),
+            ),
+            ("lamp at %", Fails(ParseError::BadTarget("".to_string()))),
+            ("lamp set", Fails(ParseError::MissingTarget)),
+            ("lamp at", Fails(ParseError::MissingTarget)),
+            (
+                "lamp at lots",
+                Fails(ParseError::BadTarget("lots".to_string())),
+            ),
+            ("lamp at -1", Fails(ParseError::BadTarget("-1".to_string()))),
+            ("lamp at 8", Set(1, 8)),
+            ("lamp at 9:00", Set(1, 9)),
+            ("lamp at ate", Set(1, 8)),
+            ("lamp at twelve", Set(1, 12)),
+            ("lamp at 250", Set(1, 250)),
+            (
+                "lamp on please",
+                Fails(ParseError::UnexpectedWords("please".to_string())),
+            ),
+            (
+                "lamp at 3 now",
+                Fails(ParseError::UnexpectedWords("now".to_string())),
+            ),
+        ];
+        for (command, expected) in cases {
+            let parsed = parse(command, &names(), &MatchingConfig::default());
+            match (&expected, &parsed) {
+                (On(uuid), Ok(p)) if matches!(p.change, Change::Action(Action::On)) => {
+                    assert_eq!(p.uuid, Uuid::from_u128(*uuid), "{:?}", command)
+                }
+                (Off(uuid), Ok(p)) if matches!(p.change, Change::Action(Action::Off)) => {
+                    assert_eq!(p.uuid, Uuid::from_u128(*uuid), "{:?}", command)
+                }
+                (Set(uuid, target), Ok(p)) => match p.change {
+                    Change::Action(Action::Set { target: t }) => {
+                        assert_eq!(p.uuid, Uuid::from_u128(*uuid), "{:?}", command);
+                        assert_eq!(t, *target, "{:?}", command);
+                    }
+                    _ => panic!("{:?} parsed as {:?}", command, p),
+                },
+                (By(uuid, steps), Ok(p)) => {
+                    assert_eq!(p.uuid, Uuid::from_u128(*uuid), "{:?}", command);
+                    assert!(
+                        matches!(p.change, Change::Adjust(Adjustment::By(s)) if s == *steps),
+                        "{:?} parsed as {:?}",
+                        command,
+                        p
+                    );
+                }
+                (Percent(uuid, percent), Ok(p)) => {
+                    assert_eq!(p.uuid, Uuid::from_u128(*uuid), "{:?}", command);
+                    assert!(
+                        matches!(p.change, Change::Adjust(Adjustment::Percent(n)) if n == *percent),
+                        "{:?} parsed as {:?}",
+                        command,
+                        p
+                    );
+                }
+                (Fails(error), Err(e)) => assert_eq!(e, error, "{:?}", command),
+                _ => panic!("{:?} parsed as {:?}", command, parsed),
+            }
+        }
+    }
+
+    #[test]
+    fn adjustments_stay_within_the_targets() {
+        let fan = TargetRange { min: 1, max: 3 };
+        let heater = TargetRange { min: 0, max: 10 };
+        // Wider than any range that's let in, which still mustn't panic
+        let wide = TargetRange {
+            min: 0,
+            max: usize::MAX,
+        };
+        let cases = [
+            (Adjustment::By(1), TargetRange::DEFAULT, 3, 4),
+            (Adjustment::By(-2), TargetRange::DEFAULT, 3, 1),
+            (Adjustment::By(3), TargetRange::DEFAULT, 6, 7),
+            (Adjustment::By(-3), TargetRange::DEFAULT, 1, 0),
+            (Adjustment::Percent(0), TargetRange::DEFAULT, 5, 0),
+            (Adjustment::Percent(40), TargetRange::DEFAULT, 0, 3),
+            (Adjustment::Percent(50), TargetRange::DEFAULT, 0, 4),
+            (Adjustment::Percent(100), TargetRange::DEFAULT, 0, 7),
+            (Adjustment::By(5), fan, 1, 3),
+            (Adjustment::By(-5), fan, 3, 1),
+            (Adjustment::Percent(0), fan, 3, 1),
+            (Adjustment::Percent(50), fan, 3, 2),
+            (Adjustment::Percent(100), fan, 1, 3),
+            (Adjustment::By(4), heater, 7, 10),
+            (Adjustment::Percent(40), heater, 0, 4),
+            (Adjustment::By(1), wide, 3, 4),
+            (Adjustment::By(-5), wide, 3, 0),
+            (Adjustment::By(1), TargetRange::DEFAULT, usize::MAX, 7),
+            (Adjustment::Percent(0), wide, 3, 0),
+        ];
+        for (adjustment, range, current, target) in cases {
+            assert_eq!(
+                adjustment.apply(current, &range),
+                target,
+                "{:?} from {}",
+                adjustment,
+                current
+            );
+        }
+    }
+
+    #[test]
+    fn finds_aliases_however_they_are_written() {
+        let config: HubConfig = toml::from_str(
+            r#"
+            [aliases]
+            "BEDROOM fan" = ["Sleepy  Fan"]
+            "#,
+        )
+        .unwrap();
+        let names = config.with_aliases(names());
+        let parsed = parse("sleepy fan on", &names, &MatchingConfig::default()).unwrap();
+        assert_eq!(parsed.uuid, Uuid::from_u128(3));
+        let parsed = parse("Sleepy Fan at 2", &names, &MatchingConfig::default()).unwrap();
+        assert_eq!(parsed.uuid, Uuid::from_u128(3));
+    }
+
+    #[test]
+    fn takes_the_longest_name() {
+        let parsed = parse("living room lamp on", &names(), &MatchingConfig::default()).unwrap();
+        assert_eq!(parsed.name, "living room lamp");
+        assert_eq!(parsed.uuid, Uuid::from_u128(2));
+    }
+
+    #[test]
+    fn finds_names_on_their_own() {
+        let matching = MatchingConfig::default();
+        let (name, uuid) = find_name("Kitchen Lights", &names(), &matching).unwrap();
+        assert_eq!(name, "kitchen light");
+        assert_eq!(uuid, Uuid::from_u128(5));
+        assert_eq!(
+            find_name("lamp on", &names(), &matching).unwrap_err(),
+            ParseError::UnknownName("lamp on".to_string())
+        );
+    }
+
+    #[test]
+    fn fuzzy_matching_can_be_turned_off() {
+        let exact = MatchingConfig {
+            fuzzy: false,
+            ..MatchingConfig::default()
+        };
+        let parsed = parse("kitchen lights on", &names(), &exact);
+        assert_eq!(
+            parsed.unwrap_err(),
+            ParseError::UnknownName("kitchen lights on".to_string())
+        );
+        assert!(parse("kitchen light on", &names(), &exact).is_ok());
+    }
+
+    #[test]
+    fn the_threshold_decides_how_close_is_close_enough() {
+        let strict = MatchingConfig {
+            threshold: 0.95,
+            ..MatchingConfig::default()
+        };
+        assert!(parse("kitchen night off", &names(), &strict).is_err());
+        assert!(parse("kitchen night off", &names(), &MatchingConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn unknown_names_are_their_own_hub_error() {
+        let error: HubError = ParseError::UnknownName("kitchen".to_string()).into();
+        assert!(matches!(error, HubError::UnknownName { name } if name == "kitchen"));
+        let error: HubError = ParseError::AmbiguousName {
+            name: "ted light".to_string(),
+            candidates: vec!["bed light".to_string(), "red light".to_string()],
+        }
+        .into();
+        assert!(matches!(error, HubError::AmbiguousName { .. }));
+        let error: HubError = ParseError::MissingTarget.into();
+        assert!(matches!(error, HubError::InvalidCommand { .. }));
+    }
+}
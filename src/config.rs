@@ -0,0 +1,724 @@
+//! The hub's settings, read from a TOML file at startup.
+//!
+//! Every setting has a default so the file only needs what differs, e.g.
+//!
+//! ```toml
+//! lock_file = "hub_app.lock"
+//! state_file = "hub_state.json"
+//!
+//! [control]
+//! socket_path = "hub_control.sock"
+//! socket_mode = 0o660
+//! socket_group = "van"
+//! listen_addr = "127.0.0.1:4000"
+//!
+//! [http]
+//! listen_addr = "0.0.0.0:8080"
+//!
+//! [discovery]
+//! subnets = ["192.168.1.0/24"]
+//! node_count = 2
+//! rediscover_interval = 60
+//!
+//! [shadow]
+//! poll_interval = 30
+//!
+//! [health]
+//! interval = 15
+//! timeout_ms = 3000
+//! slow_ms = 1000
+//! offline_after = 3
+//!
+//! [ble]
+//! local_name = "VanColleague"
+//! manufacturer_id = 0x45F1
+//!
+//! [aliases]
+//! "kitchen light" = ["galley light", "kitchen lights"]
+//!
+//! [rooms]
+//! front = ["kitchen light", "porch light"]
+//! "sleeping area" = ["bed light", "bedroom fan"]
+//!
+//! [target_ranges]
+//! "bedroom fan" = { min = 0, max = 3 }
+//! heaters = { min = 0, max = 10 }
+//!
+//! [matching]
+//! fuzzy = true
+//! threshold = 0.8
+//! ambiguity_margin = 0.05
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bluer::Uuid;
+use device::DEVICE_TYPES;
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::{watch, Mutex};
+
+use crate::devices::{LocatedDevice, TargetRange};
+use crate::discovery;
+use crate::groups;
+use crate::thread_sharing::SharedConfig;
+
+/// Where the config is looked for when no path is given
+pub const DEFAULT_CONFIG_PATH: &str = "hub.toml";
+/// The longest local name that still fits in an advertisement
+const MAX_LOCAL_NAME_LEN: usize = 29;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HubConfig {
+    /// Held while the hub runs so only one instance can
+    pub lock_file: PathBuf,
+    /// Where the last known devices are kept between runs
+    pub state_file: PathBuf,
+    pub control: ControlConfig,
+    pub http: HttpConfig,
+    pub discovery: DiscoveryConfig,
+    pub shadow: ShadowConfig,
+    pub health: HealthConfig,
+    pub ble: BleConfig,
+    /// Other names a device answers to, keyed by the device's own name
+    #[serde(deserialize_with = "normalized_keys")]
+    pub aliases: HashMap<String, Vec<String>>,
+    /// Groups of devices that can be sent a command together, keyed by the room's name
+    /// with the names of the devices in it
+    pub rooms: HashMap<String, Vec<String>>,
+    /// The targets devices take, keyed by a device's name or a type of device's group
+    /// name, for nodes that don't say. A device's own entry wins over its type's.
+    pub target_ranges: HashMap<String, TargetRange>,
+    pub matching: MatchingConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// The Unix socket admin commands are taken on, next to the lock file by default
+    pub socket_path: PathBuf,
+    /// Who can use the socket, only the owner and the socket's group by default
+    pub socket_mode: u32,
+    /// The group, by name or number, given the socket so its members can use it
+    pub socket_group: Option<String>,
+    /// Also take admin commands over TCP for remote admin, off unless set. Anyone
+    /// who can reach it can run any command.
+    pub listen_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    pub listen_addr: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// The networks to search, the local interfaces' networks are used when empty
+    pub subnets: Vec<Ipv4Net>,
+    /// How many devices to wait for before starting up
+    pub node_count: Option<usize>,
+    /// Seconds between looking for nodes that came, went or moved
+    pub rediscover_interval: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// Seconds between asking every online device for its state
+    pub poll_interval: u64,
+}
+
+/// How nodes get checked on and when they count as degraded or offline
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Seconds between checking on every node
+    pub interval: u64,
+    /// How long a node has to answer a check before it counts as failed
+    pub timeout_ms: u64,
+    /// Nodes that take longer than this to answer are degraded
+    pub slow_ms: u64,
+    /// How many checks in a row a node has to fail to be offline, fewer is degraded
+    pub offline_after: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BleConfig {
+    pub local_name: String,
+    pub manufacturer_id: u16,
+}
+
+/// How spoken or typed names that aren't exactly a device's name get matched
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchingConfig {
+    /// Whether names that are only close to a device's name match at all
+    pub fuzzy: bool,
+    /// How similar a name has to be to count, from 0 (anything) to 1 (exactly)
+    pub threshold: f64,
+    /// Names whose similarities are closer together than this are too close to call
+    pub ambiguity_margin: f64,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        HubConfig {
+            lock_file: PathBuf::from("hub_app.lock"),
+            state_file: PathBuf::from("hub_state.json"),
+            control: ControlConfig::default(),
+            http: HttpConfig::default(),
+            discovery: DiscoveryConfig::default(),
+            shadow: ShadowConfig::default(),
+            health: HealthConfig::default(),
+            ble: BleConfig::default(),
+            aliases: HashMap::new(),
+            rooms: HashMap::new(),
+            target_ranges: HashMap::new(),
+            matching: MatchingConfig::default(),
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            socket_path: PathBuf::from("hub_control.sock"),
+            socket_mode: 0o660,
+            socket_group: None,
+            listen_addr: None,
+        }
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 8080)),
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            subnets: Vec::new(),
+            node_count: None,
+            rediscover_interval: 60,
+        }
+    }
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        ShadowConfig { poll_interval: 30 }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            interval: 15,
+            timeout_ms: 3000,
+            slow_ms: 1000,
+            offline_after: 3,
+        }
+    }
+}
+
+impl Default for BleConfig {
+    fn default() -> Self {
+        BleConfig {
+            local_name: "VanColleague".to_string(),
+            manufacturer_id: 0x45F1,
+        }
+    }
+}
+
+impl Default for MatchingConfig {
+    fn default() -> Self {
+        MatchingConfig {
+            fuzzy: true,
+            threshold: 0.8,
+            ambiguity_margin: 0.05,
+        }
+    }
+}
+
+/// Why the config couldn't be used
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: toml::de::Error,
+    },
+    Invalid {
+        path: PathBuf,
+        problems: Vec<String>,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ConfigError::*;
+        match self {
+            Read { path, error } => write!(f, "Couldn't read {}: {}", path.display(), error),
+            Parse { path, error } => write!(f, "Couldn't parse {}: {}", path.display(), error),
+            Invalid { path, problems } => {
+                write!(f, "{} has problems:", path.display())?;
+                for problem in problems {
+                    write!(f, "\n    {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl HubConfig {
+    /// Reads and validates the config
+    ///
+    /// - 'path': the file to read, when None `DEFAULT_CONFIG_PATH` is used if it
+    ///   exists and the defaults otherwise
+    pub fn load(path: Option<&Path>) -> Result<HubConfig, ConfigError> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let default_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+                if !default_path.exists() {
+                    return Ok(HubConfig::default());
+                }
+                default_path
+            }
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) => return Err(ConfigError::Read { path, error }),
+        };
+        let config: HubConfig = match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(error) => return Err(ConfigError::Parse { path, error }),
+        };
+        let problems = config.problems();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid { path, problems })
+        }
+    }
+
+    /// Everything wrong with the settings that the types alone don't catch
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.discovery.rediscover_interval == 0 {
+            problems.push("discovery.rediscover_interval must be at least 1 second".to_string());
+        }
+        for subnet in self.discovery.subnets.iter() {
+            if subnet.prefix_len() < discovery::MIN_SUBNET_PREFIX_LEN {
+                problems.push(format!(
+                    "discovery.subnets {} is too big to search, use /{} or smaller",
+                    subnet,
+                    discovery::MIN_SUBNET_PREFIX_LEN
+                ));
+            }
+        }
+        if self.shadow.poll_interval == 0 {
+            problems.push("shadow.poll_interval must be at least 1 second".to_string());
+        }
+        if self.health.interval == 0 {
+            problems.push("health.interval must be at least 1 second".to_string());
+        }
+        if self.health.timeout_ms == 0 {
+            problems.push("health.timeout_ms must be at least 1".to_string());
+        }
+        if self.health.slow_ms >= self.health.timeout_ms {
+            problems.push("health.slow_ms should be below health.timeout_ms".to_string());
+        }
+        if self.health.offline_after == 0 {
+            problems.push("health.offline_after must be at least 1".to_string());
+        }
+        if self.control.listen_addr == Some(self.http.listen_addr) {
+            problems.push("control.listen_addr and http.listen_addr can't be the same".to_string());
+        }
+        if self.control.socket_path.as_os_str().is_empty() {
+            problems.push("control.socket_path can't be empty".to_string());
+        } else if self.control.socket_path == self.lock_file
+            || self.control.socket_path == self.state_file
+        {
+            problems.push(
+                "control.socket_path can't be the same as lock_file or state_file".to_string(),
+            );
+        }
+        if self.control.socket_mode > 0o777 {
+            problems.push(format!(
+                "control.socket_mode {:#o} isn't a permission mode, e.g. 0o660",
+                self.control.socket_mode
+            ));
+        }
+        if let Some(group) = &self.control.socket_group {
+            if group.trim().is_empty() {
+                problems.push("control.socket_group can't be empty".to_string());
+            }
+        }
+        if self.ble.local_name.trim().is_empty() {
+            problems.push("ble.local_name can't be empty".to_string());
+        } else if self.ble.local_name.len() > MAX_LOCAL_NAME_LEN {
+            problems.push(format!(
+                "ble.local_name can be at most {} bytes",
+                MAX_LOCAL_NAME_LEN
+            ));
+        }
+        if !(self.matching.threshold > 0.0 && self.matching.threshold <= 1.0) {
+            problems.push(format!(
+                "matching.threshold {} should be above 0 and at most 1",
+                self.matching.threshold
+            ));
+        }
+        if !(self.matching.ambiguity_margin >= 0.0 && self.matching.ambiguity_margin < 1.0) {
+            problems.push(format!(
+                "matching.ambiguity_margin {} should be at least 0 and below 1",
+                self.matching.ambiguity_margin
+            ));
+        }
+
+        let builtin_names = groups::builtin_names();
+        let room_names: Vec<String> = self.rooms.keys().map(|room| normalize_name(room)).collect();
+        // Every device the config names, an alias can't take any of their names
+        let device_names: Vec<String> = self
+            .aliases
+            .keys()
+            .cloned()
+            .chain(
+                self.rooms
+                    .values()
+                    .flatten()
+                    .map(|member| normalize_name(member)),
+            )
+            .collect();
+        let mut seen: HashMap<String, &String> = HashMap::new();
+        for (name, aliases) in self.aliases.iter() {
+            for alias in aliases {
+                let alias = normalize_name(alias);
+                if alias.is_empty() {
+                    problems.push(format!("aliases for '{}' can't be empty", name));
+                } else if builtin_names.contains(&alias) || room_names.contains(&alias) {
+                    problems.push(format!(
+                        "alias '{}' for '{}' is already a group or room",
+                        alias, name
+                    ));
+                } else if alias != *name && device_names.contains(&alias) {
+                    problems.push(format!(
+                        "alias '{}' for '{}' is already a device's name",
+                        alias, name
+                    ));
+                } else if let Some(other) = seen.insert(alias.clone(), name) {
+                    if other != name {
+                        problems.push(format!(
+                            "alias '{}' is used for both '{}' and '{}'",
+                            alias, other, name
+                        ));
+                    }
+                }
+            }
+        }
+        for (room, members) in self.rooms.iter() {
+            let name = normalize_name(room);
+            if name.is_empty() {
+                problems.push("room names can't be empty".to_string());
+            } else if builtin_names.contains(&name) {
+                problems.push(format!(
+                    "'{}' is already a group, pick another room name",
+                    room
+                ));
+            }
+            if members
+                .iter()
+                .any(|member| normalize_name(member).is_empty())
+            {
+                problems.push(format!("the devices in room '{}' can't be empty", room));
+            }
+        }
+        for (name, range) in self.target_ranges.iter() {
+            if normalize_name(name).is_empty() {
+                problems.push("target_ranges names can't be empty".to_string());
+            }
+            if range.min > range.max {
+                problems.push(format!(
+                    "target_ranges for '{}' has its min above its max",
+                    name
+                ));
+            } else if range.max > TargetRange::MAX_TARGET {
+                problems.push(format!(
+                    "target_ranges for '{}' can go up to {} at most",
+                    name,
+                    TargetRange::MAX_TARGET
+                ));
+            }
+        }
+        problems
+    }
+
+    /// The targets the device takes: its own entry in `target_ranges`, then what its
+    /// node said, then its type's entry, and `TargetRange::DEFAULT` failing all that
+    pub fn target_range(&self, located_device: &LocatedDevice) -> TargetRange {
+        let range_for = |name: &str| {
+            let name = normalize_name(name);
+            self.target_ranges
+                .iter()
+                .find(|(n, _)| normalize_name(n) == name)
+                .map(|(_, range)| *range)
+        };
+        let type_name = DEVICE_TYPES
+            .iter()
+            .find(|(t, _, _)| Some(*t) == located_device.device.device_type)
+            .map(|(_, name, _)| *name);
+        range_for(&located_device.device.name)
+            .or(located_device.target_range)
+            .or_else(|| type_name.and_then(range_for))
+            .unwrap_or(TargetRange::DEFAULT)
+    }
+
+    /// Adds every alias to a list of names, pointing at the same uuid as the name
+    /// it's an alias for. An alias that's another device's name is left out, the
+    /// device keeps its name.
+    pub fn with_aliases(&self, names: Vec<(String, Uuid)>) -> Vec<(String, Uuid)> {
+        let taken: Vec<String> = names.iter().map(|(name, _)| normalize_name(name)).collect();
+        let mut all_names = names.clone();
+        for (name, uuid) in names.iter() {
+            if let Some(aliases) = self.aliases.get(&normalize_name(name)) {
+                for alias in aliases {
+                    let alias = normalize_name(alias);
+                    if !taken.contains(&alias) {
+                        all_names.push((alias, *uuid));
+                    }
+                }
+            }
+        }
+        all_names
+    }
+}
+
+/// Settings given on the command line, which win over the config file every time it's read
+#[derive(Debug, Default, Clone)]
+pub struct ConfigOverrides {
+    pub node_count: Option<usize>,
+    pub subnets: Option<Vec<Ipv4Net>>,
+    pub rediscover_interval: Option<u64>,
+    pub state_file: Option<PathBuf>,
+}
+
+impl ConfigOverrides {
+    pub fn apply(&self, config: &mut HubConfig) {
+        if let Some(node_count) = self.node_count {
+            config.discovery.node_count = Some(node_count);
+        }
+        if let Some(subnets) = &self.subnets {
+            config.discovery.subnets = subnets.clone();
+        }
+        if let Some(rediscover_interval) = self.rediscover_interval {
+            config.discovery.rediscover_interval = rediscover_interval;
+        }
+        if let Some(state_file) = &self.state_file {
+            config.state_file = state_file.clone();
+        }
+    }
+}
+
+/// What happened to the settings that changed in a reload
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct ReloadReport {
+    /// Changed settings that are now in effect
+    pub applied: Vec<String>,
+    /// Changed settings that only take effect after a restart
+    pub needs_restart: Vec<String>,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.applied.is_empty() && self.needs_restart.is_empty() {
+            return write!(f, "nothing changed");
+        }
+        if !self.applied.is_empty() {
+            write!(f, "applied: {}", self.applied.join(", "))?;
+        }
+        if !self.needs_restart.is_empty() {
+            if !self.applied.is_empty() {
+                write!(f, "; ")?;
+            }
+            write!(f, "needs a restart: {}", self.needs_restart.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl HubConfig {
+    /// Takes the settings that can change while running from `new`, leaving the rest
+    /// as they are, and reports what changed
+    fn apply_reload(&mut self, new: HubConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        let mut check = |name: &str, changed: bool, hot: bool| {
+            if changed {
+                match hot {
+                    true => report.applied.push(name.to_string()),
+                    false => report.needs_restart.push(name.to_string()),
+                }
+            }
+        };
+        check("lock_file", self.lock_file != new.lock_file, false);
+        check("state_file", self.state_file != new.state_file, false);
+        check(
+            "control.socket_path",
+            self.control.socket_path != new.control.socket_path,
+            false,
+        );
+        check(
+            "control.socket_mode",
+            self.control.socket_mode != new.control.socket_mode,
+            false,
+        );
+        check(
+            "control.socket_group",
+            self.control.socket_group != new.control.socket_group,
+            false,
+        );
+        check(
+            "control.listen_addr",
+            self.control.listen_addr != new.control.listen_addr,
+            false,
+        );
+        check(
+            "http.listen_addr",
+            self.http.listen_addr != new.http.listen_addr,
+            false,
+        );
+        check(
+            "discovery.node_count",
+            self.discovery.node_count != new.discovery.node_count,
+            false,
+        );
+        check(
+            "discovery.subnets",
+            self.discovery.subnets != new.discovery.subnets,
+            true,
+        );
+        check(
+            "discovery.rediscover_interval",
+            self.discovery.rediscover_interval != new.discovery.rediscover_interval,
+            true,
+        );
+        check(
+            "shadow.poll_interval",
+            self.shadow.poll_interval != new.shadow.poll_interval,
+            true,
+        );
+        check("health", self.health != new.health, true);
+        check(
+            "ble.local_name",
+            self.ble.local_name != new.ble.local_name,
+            true,
+        );
+        check(
+            "ble.manufacturer_id",
+            self.ble.manufacturer_id != new.ble.manufacturer_id,
+            true,
+        );
+        check("aliases", self.aliases != new.aliases, true);
+        check("rooms", self.rooms != new.rooms, true);
+        check(
+            "target_ranges",
+            self.target_ranges != new.target_ranges,
+            true,
+        );
+        check("matching", self.matching != new.matching, true);
+
+        self.discovery.subnets = new.discovery.subnets;
+        self.discovery.rediscover_interval = new.discovery.rediscover_interval;
+        self.shadow = new.shadow;
+        self.health = new.health;
+        self.ble = new.ble;
+        self.aliases = new.aliases;
+        self.rooms = new.rooms;
+        self.target_ranges = new.target_ranges;
+        self.matching = new.matching;
+        report
+    }
+}
+
+/// Re-reads the config file on demand and lets the running parts of the hub know
+#[derive(Debug, Clone)]
+pub struct ConfigReloader {
+    path: Option<PathBuf>,
+    overrides: ConfigOverrides,
+    shared_config: Arc<Mutex<SharedConfig>>,
+    changes: Arc<watch::Sender<u64>>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: Option<PathBuf>,
+        overrides: ConfigOverrides,
+        shared_config: Arc<Mutex<SharedConfig>>,
+    ) -> ConfigReloader {
+        let (changes, _) = watch::channel(0);
+        ConfigReloader {
+            path,
+            overrides,
+            shared_config,
+            changes: Arc::new(changes),
+        }
+    }
+
+    /// Gets notified every time a reload changes something
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    /// Reads the config file again and puts whatever can change while running in place
+    ///
+    /// A config that doesn't load leaves the current settings untouched.
+    pub async fn reload(&self) -> Result<ReloadReport, ConfigError> {
+        let mut new = HubConfig::load(self.path.as_deref())?;
+        self.overrides.apply(&mut new);
+        let report = self.shared_config.lock().await.hub.apply_reload(new);
+        if !report.applied.is_empty() {
+            self.changes.send_modify(|version| *version += 1);
+        }
+        Ok(report)
+    }
+}
+
+/// Reads a table keyed by names with every name normalized, so it can be looked up
+/// however the name was written. Entries for the same name are put together.
+fn normalized_keys<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let table = HashMap::<String, Vec<String>>::deserialize(deserializer)?;
+    let mut normalized: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, values) in table {
+        normalized
+            .entry(normalize_name(&name))
+            .or_default()
+            .extend(values);
+    }
+    Ok(normalized)
+}
+
+/// Lower cases the name and squashes its whitespace so it compares like spoken words
+pub fn normalize_name(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
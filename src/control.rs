@@ -0,0 +1,591 @@
+//! The admin protocol spoken on the control socket.
+//!
+//! The hub always listens on a Unix socket, whose file mode and group decide who can
+//! manage it, and on TCP only when `control.listen_addr` is set.
+//!
+//! Clients send one command per line and get one line of JSON back for each, either
+//! `{"ok":true,"result":...}` or `{"ok":false,"error":{"error":"<kind>","message":"..."}}`.
+//!
+//! - `devices`: every known device along with where it is and whether it's online
+//! - `status <name>`: the device's last known state and how old it is
+//! - `refresh <name>`: asks the device's node for its current state
+//! - `health`: whether every device's node is online, degraded or offline and when it
+//!   last answered
+//! - `send <name> <action> [target]`: has the device carry out the action
+//! - `rediscover`: looks for nodes right away instead of waiting for the next round
+//! - `reload`: re-reads the config file
+//! - `shutdown`: stops the hub
+//! - `watch`: replies once, then streams a line of JSON for every `HubEvent` until the
+//!   client hangs up
+//! - `help`: lists the commands
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bluer::Uuid;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::{broadcast, Mutex},
+    time::timeout,
+};
+
+use device::Device;
+
+use crate::command_parser;
+use crate::config::{ConfigReloader, ControlConfig};
+use crate::devices::LocatedDevice;
+use crate::discovery;
+use crate::error::HubError;
+use crate::groups;
+use crate::health::{DeviceHealth, HealthMonitor};
+use crate::registry::DeviceRegistry;
+use crate::shadow::{self, DeviceShadow, ShadowState};
+use crate::shutdown::Shutdown;
+use crate::thread_sharing::{
+    CommandBus, HubEvent, HubEvents, HubRequest, HubResponse, MemberResult, SharedConfig,
+};
+
+pub const SHUTDOWN_COMMAND: &str = "shutdown";
+pub const RELOAD_COMMAND: &str = "reload";
+pub const WATCH_COMMAND: &str = "watch";
+/// How long a client waits on the hub to reply, longer than the hub itself waits on a node
+const REPLY_TIMEOUT: Duration = Duration::from_secs(20);
+/// How long a client waits on `rediscover`, which can be probing a whole /20
+const REDISCOVER_REPLY_TIMEOUT: Duration = Duration::from_secs(180);
+const HELP: &str =
+    "devices | status <name> | refresh <name> | health | send <name> <action> [target] | rediscover | reload | shutdown | watch";
+
+/// Everything the admin commands need to get at
+#[derive(Debug, Clone)]
+pub struct ControlContext {
+    pub shutdown: Shutdown,
+    pub command_bus: CommandBus,
+    pub reloader: ConfigReloader,
+    pub registry: DeviceRegistry,
+    pub shadow: DeviceShadow,
+    pub health: HealthMonitor,
+    pub shared_config: Arc<Mutex<SharedConfig>>,
+    pub events: HubEvents,
+}
+
+/// The answer to a single admin command
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+impl ControlReply {
+    fn ok(result: Value) -> ControlReply {
+        ControlReply {
+            ok: true,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(kind: &str, message: String) -> ControlReply {
+        ControlReply {
+            ok: false,
+            result: None,
+            error: Some(json!({ "error": kind, "message": message })),
+        }
+    }
+
+    fn hub_error(error: &HubError) -> ControlReply {
+        let mut body = serde_json::to_value(error).unwrap();
+        body["message"] = Value::String(error.to_string());
+        ControlReply {
+            ok: false,
+            result: None,
+            error: Some(body),
+        }
+    }
+
+    /// The error's message, or an empty string for a successful reply
+    pub fn error_message(&self) -> String {
+        match &self.error {
+            Some(error) => match error.get("message").and_then(|m| m.as_str()) {
+                Some(message) => message.to_string(),
+                None => error.to_string(),
+            },
+            None => String::new(),
+        }
+    }
+}
+
+/// Binds the Unix socket at `path` and gives it the configured mode and group
+///
+/// Only call this while holding the lock file, a socket file that's already there is
+/// assumed to be left over from a hub that didn't clean up and is removed. Anything
+/// else at the path is left alone and binding fails.
+pub fn bind_unix_socket(path: &Path, control_config: &ControlConfig) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Something other than a socket is already there",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    // The socket starts out usable by its owner alone until the configured mode is set,
+    // the umask is the whole process's so it's put back straight away
+    let umask = unsafe { libc::umask(0o177) };
+    let bound = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = bound?;
+    fs::set_permissions(path, fs::Permissions::from_mode(control_config.socket_mode))?;
+    if let Some(group) = &control_config.socket_group {
+        match group_id(group) {
+            Some(gid) => chown(path, None, Some(gid))?,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No group named '{}'", group),
+                ))
+            }
+        }
+    }
+    Ok(listener)
+}
+
+/// Looks a group up by its number or its name
+fn group_id(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    // Only read straight away, before anything else can look a group up
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        None
+    } else {
+        Some(unsafe { (*entry).gr_gid })
+    }
+}
+
+/// Removes the socket file once the hub is done with it
+pub fn remove_unix_socket(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Accepts admin connections on the Unix socket until the process ends
+pub async fn run_unix_control_server(listener: UnixListener, context: ControlContext) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_client(stream, context.clone()));
+            }
+            Err(e) => eprintln!("Connection failed: {}", e),
+        }
+    }
+}
+
+/// Accepts admin connections over TCP until the process ends
+pub async fn run_tcp_control_server(listener: TcpListener, context: ControlContext) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle_client(stream, context.clone()));
+            }
+            Err(e) => eprintln!("Connection failed: {}", e),
+        }
+    }
+}
+
+/// Answers every command line the client sends until it hangs up, asks for a shutdown
+/// or starts watching
+async fn handle_client<S>(stream: S, context: ControlContext)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Failed to receive data: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == WATCH_COMMAND {
+            // Subscribe before replying so nothing is missed in between
+            let events = context.events.subscribe();
+            if write_line(&mut writer, &ControlReply::ok(json!("watching")))
+                .await
+                .is_ok()
+            {
+                stream_events(&mut lines, &mut writer, events).await;
+            }
+            break;
+        }
+        if line == SHUTDOWN_COMMAND {
+            // Reply first, the hub may well be gone soon after
+            let _ = write_line(&mut writer, &ControlReply::ok(json!("shutting down"))).await;
+            let _ = writer.flush().await;
+            context.shutdown.trigger();
+            break;
+        }
+        let reply = handle_command(line, &context).await;
+        if write_line(&mut writer, &reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends the value to the client as a line of JSON
+async fn write_line<W, T>(writer: &mut W, value: &T) -> Result<(), ()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut text = serde_json::to_string(value).unwrap();
+    text.push('\n');
+    match writer.write_all(text.as_bytes()).await {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Failed to send reply: {}", e);
+            Err(())
+        }
+    }
+}
+
+/// Passes every event along to a watching client until it hangs up
+async fn stream_events<R, W>(
+    lines: &mut Lines<R>,
+    writer: &mut W,
+    mut events: broadcast::Receiver<HubEvent>,
+) where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if write_line(writer, &event).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    eprintln!("A watcher fell behind and missed {} events", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Anything the client sends while watching is ignored, we only care when it's gone
+            line = lines.next_line() => match line {
+                Ok(Some(_)) => {}
+                _ => break,
+            },
+        }
+    }
+}
+
+/// Carries out a single admin command
+async fn handle_command(line: &str, context: &ControlContext) -> ControlReply {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["devices"] => {
+            let mut devices: Vec<_> = context.registry.snapshot().await.into_values().collect();
+            devices.sort_by(|a, b| a.device.name.cmp(&b.device.name));
+            ControlReply::ok(json!(devices))
+        }
+        [command @ ("status" | "refresh"), name @ ..] if !name.is_empty() => {
+            let uuid = match find_device(context, &name.join(" ")).await {
+                Ok(uuid) => uuid,
+                Err(e) => return ControlReply::hub_error(&e),
+            };
+            let refresh = *command == "refresh";
+            match shadow::read(&context.shadow, &context.command_bus, uuid, refresh).await {
+                Ok(state) => ControlReply::ok(json!(state)),
+                Err(e) => ControlReply::hub_error(&e),
+            }
+        }
+        ["health"] => ControlReply::ok(json!(context.health.report(&context.registry).await)),
+        ["send", rest @ ..] if !rest.is_empty() => {
+            let request = match parse_send(context, rest).await {
+                Ok(request) => request,
+                Err(e) => return ControlReply::hub_error(&e),
+            };
+            match context.command_bus.submit(request).await {
+                Ok(response) => ControlReply::ok(response_value(response)),
+                Err(e) => ControlReply::hub_error(&e),
+            }
+        }
+        ["rediscover"] => {
+            let summary =
+                discovery::rediscover_now(&context.registry, &context.shared_config).await;
+            ControlReply::ok(json!(summary))
+        }
+        [RELOAD_COMMAND] => match context.reloader.reload().await {
+            Ok(report) => ControlReply::ok(json!(report)),
+            Err(e) => ControlReply::error("config", e.to_string()),
+        },
+        ["help"] => ControlReply::ok(json!(HELP)),
+        _ => ControlReply::hub_error(&HubError::invalid_command(format!(
+            "Unknown command '{}', try: {}",
+            line, HELP
+        ))),
+    }
+}
+
+/// Devices come back as themselves, anything else as the whole response
+fn response_value(response: HubResponse) -> Value {
+    match response {
+        HubResponse::Device { device } => json!(device),
+        HubResponse::Group { results } => json!(results),
+        other => json!(other),
+    }
+}
+
+/// Finds the uuid for a device name or one of its aliases, or the closest one
+async fn find_device(context: &ControlContext, name: &str) -> Result<Uuid, HubError> {
+    let names = device_names(context).await;
+    let matching = context.shared_config.lock().await.hub.matching;
+    let (_, uuid) = command_parser::find_name(name, &names, &matching)?;
+    Ok(uuid)
+}
+
+async fn device_names(context: &ControlContext) -> Vec<(String, Uuid)> {
+    groups::command_names(&context.registry, &context.shared_config).await
+}
+
+/// Parses `<name> <action> [target]` the same way spoken commands are
+async fn parse_send(context: &ControlContext, words: &[&str]) -> Result<HubRequest, HubError> {
+    let names = device_names(context).await;
+    let matching = context.shared_config.lock().await.hub.matching;
+    let parsed = command_parser::parse_words(words, &names, &matching)?;
+    Ok(parsed.request())
+}
+
+/// Where a client finds the running hub
+#[derive(Debug, Clone)]
+pub enum ControlEndpoint {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for ControlEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlEndpoint::Unix(path) => write!(f, "{}", path.display()),
+            ControlEndpoint::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// A connection to a running hub's control socket
+pub struct ControlClient {
+    lines: Lines<BufReader<Box<dyn AsyncRead + Send + Unpin>>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+}
+
+impl ControlClient {
+    pub async fn connect(endpoint: &ControlEndpoint) -> Result<ControlClient, String> {
+        let connected = match endpoint {
+            ControlEndpoint::Unix(path) => UnixStream::connect(path).await.map(|stream| {
+                let (reader, writer) = stream.into_split();
+                ControlClient::new(Box::new(reader), Box::new(writer))
+            }),
+            ControlEndpoint::Tcp(addr) => TcpStream::connect(addr).await.map(|stream| {
+                let (reader, writer) = stream.into_split();
+                ControlClient::new(Box::new(reader), Box::new(writer))
+            }),
+        };
+        match connected {
+            Ok(client) => Ok(client),
+            Err(e) => Err(format!("Couldn't reach the hub at {}: {}", endpoint, e)),
+        }
+    }
+
+    fn new(
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+    ) -> ControlClient {
+        ControlClient {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+
+    /// Sends a command and waits for its reply, giving up if the hub takes too long
+    pub async fn request(&mut self, command: &str) -> Result<ControlReply, String> {
+        let reply_timeout = match command.trim() {
+            "rediscover" => REDISCOVER_REPLY_TIMEOUT,
+            _ => REPLY_TIMEOUT,
+        };
+        match timeout(reply_timeout, self.exchange(command)).await {
+            Ok(reply) => reply,
+            Err(_) => Err("Timed out waiting for the hub to reply".to_string()),
+        }
+    }
+
+    async fn exchange(&mut self, command: &str) -> Result<ControlReply, String> {
+        let line = format!("{}\n", command);
+        if let Err(e) = self.writer.write_all(line.as_bytes()).await {
+            return Err(format!("Couldn't send the command: {}", e));
+        }
+        match self.next_line().await? {
+            Some(reply) => match serde_json::from_str(&reply) {
+                Ok(reply) => Ok(reply),
+                Err(e) => Err(format!("Couldn't understand the reply: {}", e)),
+            },
+            None => Err("The hub hung up without replying".to_string()),
+        }
+    }
+
+    /// Waits for the next event after a `watch`, `None` once the hub hangs up
+    pub async fn next_event(&mut self) -> Result<Option<HubEvent>, String> {
+        match self.next_line().await? {
+            Some(line) => match serde_json::from_str(&line) {
+                Ok(event) => Ok(Some(event)),
+                Err(e) => Err(format!("Couldn't understand the event: {}", e)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn next_line(&mut self) -> Result<Option<String>, String> {
+        match self.lines.next_line().await {
+            Ok(line) => Ok(line),
+            Err(e) => Err(format!("Couldn't read from the hub: {}", e)),
+        }
+    }
+}
+
+/// Sends a single command to a running hub and waits for its reply
+pub async fn send_control_command(
+    endpoint: &ControlEndpoint,
+    command: &str,
+) -> Result<ControlReply, String> {
+    ControlClient::connect(endpoint)
+        .await?
+        .request(command)
+        .await
+}
+
+/// One line for each member of a group a command went to
+fn describe_group_results(result: &Value) -> String {
+    match serde_json::from_value::<Vec<MemberResult>>(result.clone()) {
+        Ok(results) if results.is_empty() => "Nothing is in that group yet".to_string(),
+        Ok(results) => results
+            .iter()
+            .map(|member| match (&member.device, &member.error) {
+                (Some(device), _) => format!("{} is at {}", member.name, device.target),
+                (None, Some(error)) => format!("{} failed: {}", member.name, error),
+                (None, None) => format!("{} is done", member.name),
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        Err(_) => result.to_string(),
+    }
+}
+
+/// How long ago something was, roughly
+fn describe_age(age_secs: u64) -> String {
+    match age_secs {
+        0 => "just now".to_string(),
+        1 => "a second ago".to_string(),
+        2..=119 => format!("{} seconds ago", age_secs),
+        120..=7199 => format!("{} minutes ago", age_secs / 60),
+        _ => format!("{} hours ago", age_secs / 3600),
+    }
+}
+
+/// When a node was last heard from, given in seconds since the Unix epoch
+fn describe_last_seen(last_seen: Option<u64>) -> String {
+    let last_seen = match last_seen {
+        Some(last_seen) => UNIX_EPOCH + Duration::from_secs(last_seen),
+        None => return "not seen yet".to_string(),
+    };
+    match SystemTime::now().duration_since(last_seen) {
+        Ok(age) => format!("seen {}", describe_age(age.as_secs())),
+        Err(_) => "seen just now".to_string(),
+    }
+}
+
+/// Puts a successful reply to one of the client commands into words
+pub fn describe_result(command: &str, result: &Value) -> String {
+    match command {
+        "devices" => match serde_json::from_value::<Vec<LocatedDevice>>(result.clone()) {
+            Ok(devices) if devices.is_empty() => "No devices found yet".to_string(),
+            Ok(devices) => devices
+                .iter()
+                .map(|ld| {
+                    format!(
+                        "{:<24} {:<36} {:<21} {}",
+                        ld.device.name,
+                        ld.device.uuid,
+                        ld.ip,
+                        if ld.online { "online" } else { "offline" }
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Err(_) => result.to_string(),
+        },
+        "status" | "refresh" => match serde_json::from_value::<ShadowState>(result.clone()) {
+            Ok(state) => format!(
+                "{} is at {}, as of {}",
+                state.device.name,
+                state.device.target,
+                describe_age(state.age_secs)
+            ),
+            Err(_) => result.to_string(),
+        },
+        "health" => match serde_json::from_value::<Vec<DeviceHealth>>(result.clone()) {
+            Ok(report) if report.is_empty() => "No devices found yet".to_string(),
+            Ok(report) => report
+                .iter()
+                .map(|health| {
+                    format!(
+                        "{:<24} {:<21} {:<9} {}",
+                        health.name,
+                        health.ip,
+                        health.state,
+                        describe_last_seen(health.last_seen)
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Err(_) => result.to_string(),
+        },
+        "send" => match serde_json::from_value::<Device>(result.clone()) {
+            Ok(device) => format!("{} is at {}", device.name, device.target),
+            Err(_) => match result {
+                Value::Array(_) => describe_group_results(result),
+                Value::String(text) => text.clone(),
+                _ => "ok".to_string(),
+            },
+        },
+        _ => match result {
+            Value::String(text) => text.clone(),
+            _ => result.to_string(),
+        },
+    }
+}
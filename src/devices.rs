@@ -1,8 +1,7 @@
-use futures::future::join_all;
 use std::collections::HashMap;
-use std::process::Command;
+use std::fmt;
 
-use regex::Regex;
+use ipnet::Ipv4Net;
 use reqwest;
 
 use bluer::Uuid;
@@ -10,120 +9,245 @@ use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_json::{Error, Value};
 
-use device::Device;
+use device::{Action, Device};
+use lazy_static::lazy_static;
+
+use crate::discovery;
+use crate::error::HubError;
+
+/// How long to wait on a node before calling it unreachable
+const NODE_TIMEOUT_SECS: u64 = 5;
+
+lazy_static! {
+    static ref NODE_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(NODE_TIMEOUT_SECS))
+        .build()
+        .expect("Failed to build the node HTTP client");
+    /// The bad devices each node was last reported for, so they're only logged when
+    /// they change rather than on every check
+    static ref REPORTED_PROBLEMS: std::sync::Mutex<HashMap<String, Vec<String>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
 
 /// A struct to store a device along with the IP address where it's located
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct LocatedDevice {
     pub device: Device,
     pub ip: String,
+    /// Whether the node answered the last time we looked for it
+    #[serde(default = "default_online")]
+    pub online: bool,
+    /// The targets the node says the device takes, when it says
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_range: Option<TargetRange>,
+}
+
+fn default_online() -> bool {
+    true
+}
+
+/// The targets a device can be set to, `min` though `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl TargetRange {
+    /// What devices take unless the node or the config says otherwise
+    pub const DEFAULT: TargetRange = TargetRange { min: 0, max: 7 };
+    /// The highest target a range can go up to, the BLE binary format only has a byte
+    /// for it
+    pub const MAX_TARGET: usize = u8::MAX as usize;
+
+    /// Whether the range can be used, as given by a node or the config
+    pub fn is_valid(&self) -> bool {
+        self.min <= self.max && self.max <= TargetRange::MAX_TARGET
+    }
+
+    pub fn contains(&self, target: usize) -> bool {
+        self.min <= target && target <= self.max
+    }
+
+    /// The closest target in the range
+    pub fn clamp(&self, target: i64) -> usize {
+        match usize::try_from(target) {
+            Ok(target) => target.max(self.min).min(self.max),
+            Err(_) => self.min,
+        }
+    }
+}
+
+impl fmt::Display for TargetRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} though {}", self.min, self.max)
+    }
 }
 
 /// Get all of the devices along with their locateions.
 ///
+/// - 'subnets': the networks to search, the local interfaces' networks are used when empty
+///
 /// Returns a HashMap where the keys are device Uuids
 /// and values are LocatedDevices
-pub async fn get_devices() -> HashMap<Uuid, LocatedDevice> {
-    let ips = get_ips();
-
-    let mut devices: HashMap<Uuid, LocatedDevice> = HashMap::new();
-
-    let futures: Vec<_> = ips
-        .into_iter()
-        .map(|ip| tokio::spawn(get_node_devices(ip)))
-        .collect();
-
-    let results: Vec<_> = join_all(futures).await;
-
-    for result in results {
-        if result.is_ok() {
-            let result = result.unwrap();
-            if result.is_some() {
-                let result = result.unwrap();
-                for (uuid, located_device) in result {
-                    devices.insert(uuid, located_device);
-                }
-            }
-        }
-    }
-    devices
+pub async fn get_devices(subnets: &[Ipv4Net]) -> HashMap<Uuid, LocatedDevice> {
+    discovery::discover(subnets).await
 }
 
 /// Gets the status of a device
 ///
 /// - 'ip': the ip address of the node that the device is on
 /// - 'uuid': the uuid of the device
-pub async fn get_device_status(ip: &String, uuid: &Uuid) -> Result<Device, String> {
-    let url = format!("http://{}/status?uuid={}", ip, uuid.to_string());
-    dbg!(&url);
-    let device_text = match reqwest::get(&url).await {
-        Ok(response) => match response.text().await {
-            Ok(maybe_device_text) => maybe_device_text,
-            Err(_) => return Err("No response or something in get_device_status".to_string()),
-        },
-        Err(_) => return Err("No response or something in get_device_status".to_string()),
+pub async fn get_device_status(ip: &String, uuid: &Uuid) -> Result<Device, HubError> {
+    let url = format!("http://{}/status?uuid={}", ip, uuid);
+    log::debug!("Asking for {}", &url);
+    let unreachable = |e: reqwest::Error| HubError::NodeUnreachable {
+        ip: ip.clone(),
+        reason: e.to_string(),
+    };
+    let response = NODE_CLIENT.get(&url).send().await.map_err(unreachable)?;
+    let device_text = response.text().await.map_err(unreachable)?;
+    log::debug!("{} answered {}", &url, &device_text);
+
+    Device::from_json(&device_text).map_err(|_| HubError::InvalidNodeResponse {
+        ip: ip.clone(),
+        reason: format!("Expected device {} but got: {}", uuid, device_text),
+    })
+}
+
+/// Sends an action to a device on its node and returns the device's resulting state
+///
+/// - 'ip': the ip address of the node that the device is on
+/// - 'uuid': the uuid of the device
+/// - 'action': what the device should do
+pub async fn send_command(ip: &String, uuid: &Uuid, action: &Action) -> Result<Device, HubError> {
+    let target = match action.get_target() {
+        Some(t) => t.to_string(),
+        None => "".to_string(),
+    };
+    let url = format!(
+        "http://{}/command?uuid={}&action={}&target={}",
+        ip,
+        uuid,
+        action.to_str(),
+        &target,
+    );
+    log::debug!("Sending {}", &url);
+    let unreachable = |e: reqwest::Error| HubError::NodeUnreachable {
+        ip: ip.clone(),
+        reason: e.to_string(),
     };
-    dbg!(&device_text);
+    let response = NODE_CLIENT.get(&url).send().await.map_err(unreachable)?;
+    let status = response.status();
+    let body = response.text().await.map_err(unreachable)?;
+    if !status.is_success() {
+        return Err(HubError::NodeRejected {
+            ip: ip.clone(),
+            status: status.as_u16(),
+            reason: body,
+        });
+    }
 
-    match Device::from_json(&device_text) {
+    // Nodes that don't answer with the updated device get asked for it
+    match Device::from_json(&body) {
         Ok(d) => Ok(d),
-        _ => Err("Oops, didn't get the device as expected, apparent IP or name issue".to_string()),
+        _ => get_device_status(ip, uuid).await,
     }
 }
 
-async fn get_node_devices(ip: String) -> Option<HashMap<Uuid, LocatedDevice>> {
+/// Asks whatever is at the ip for its devices
+///
+/// Returns None when there's no node there, or it didn't answer with a list of devices.
+/// Devices in the list that can't be made sense of are left out rather than costing the
+/// node the rest.
+pub async fn get_node_devices(ip: String) -> Option<HashMap<Uuid, LocatedDevice>> {
     let url = format!("http://{}/devices", ip);
-    dbg!(&url);
-    let device_text = match reqwest::get(&url).await {
-        Ok(response) => match response.text().await {
-            Ok(maybe_device_text) => maybe_device_text,
-            Err(_) => return None,
-        },
+    let response = match NODE_CLIENT.get(&url).send().await {
+        Ok(response) if response.status().is_success() => response,
+        _ => return None,
+    };
+    let device_text = match response.text().await {
+        Ok(maybe_device_text) => maybe_device_text,
         Err(_) => return None,
     };
-    let device_json: Value = serde_json::from_str(device_text.as_str()).unwrap();
-    let mut located_devices: HashMap<Uuid, LocatedDevice> = HashMap::new();
-    if device_json.is_object() {
-        for (_, value) in device_json.as_object().unwrap() {
-            let device = Device::from_json(&value.to_string()).unwrap();
-            located_devices.insert(
-                device.uuid.clone(),
-                LocatedDevice {
-                    device,
-                    ip: ip.clone(),
-                },
-            );
+    let device_json: Value = match serde_json::from_str(device_text.as_str()) {
+        Ok(json) => json,
+        Err(_) => return None,
+    };
+    let (located_devices, problems) = match parse_node_devices(&ip, &device_json) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            report_problems(&ip, vec![e]);
+            return None;
         }
-    }
+    };
+    // One bad device shouldn't cost the node its others
+    report_problems(&ip, problems);
     Some(located_devices)
 }
 
-fn run_nmap() -> String {
-    let output = Command::new("nmap")
-        .arg("-sn")
-        .arg("192.168.1.0/24")
-        .output()
-        .expect("Failed to execute command");
-
-    if output.status.success() {
-        String::from_utf8_lossy(&output.stdout).into_owned()
-    } else {
-        panic!(
-            "Command failed: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
+/// Logs what's wrong with a node's devices, unless it's what was logged for it last time
+fn report_problems(ip: &str, problems: Vec<String>) {
+    let mut reported = REPORTED_PROBLEMS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let last = reported.get(ip).map(Vec::as_slice).unwrap_or_default();
+    if last == problems.as_slice() {
+        return;
     }
+    for problem in problems.iter() {
+        eprintln!("Skipping devices from {}: {}", ip, problem);
+    }
+    reported.insert(ip.to_string(), problems);
 }
 
-fn extract_ips(nmap_output: &str) -> Vec<String> {
-    let ip_regex = Regex::new(r"\b(?:[0-9]{1,3}\.){3}[0-9]{1,3}\b").unwrap();
-    ip_regex
-        .find_iter(nmap_output)
-        .map(|match_| match_.as_str().to_string())
-        .collect()
+/// Turns a node's `/devices` payload into located devices, along with why each entry
+/// that was left out couldn't be used
+///
+/// - 'ip': the address of the node the payload came from
+/// - 'device_json': the payload, an object with a device for every value, which can
+///   also have the device's `target_range`, e.g. `{"min": 0, "max": 3}`
+///
+/// Returns an error when the payload isn't an object of devices at all
+pub fn parse_node_devices(
+    ip: &str,
+    device_json: &Value,
+) -> Result<(HashMap<Uuid, LocatedDevice>, Vec<String>), String> {
+    let object = match device_json.as_object() {
+        Some(object) => object,
+        None => return Err("Expected an object of devices".to_string()),
+    };
+    let mut located_devices: HashMap<Uuid, LocatedDevice> = HashMap::new();
+    let mut problems = Vec::new();
+    for (key, value) in object {
+        match parse_node_device(ip, key, value) {
+            Ok(located_device) => {
+                located_devices.insert(located_device.device.uuid, located_device);
+            }
+            Err(e) => problems.push(e),
+        }
+    }
+    Ok((located_devices, problems))
 }
 
-fn get_ips() -> Vec<String> {
-    let nmap_output = run_nmap();
-    extract_ips(&nmap_output)
+/// Turns one entry of a node's `/devices` payload into a located device
+fn parse_node_device(ip: &str, key: &str, value: &Value) -> Result<LocatedDevice, String> {
+    let device = match Device::from_json(&value.to_string()) {
+        Ok(device) => device,
+        Err(_) => return Err(format!("Device '{}' isn't a valid device", key)),
+    };
+    let target_range = match value.get("target_range") {
+        Some(range) => match serde_json::from_value::<TargetRange>(range.clone()) {
+            Ok(range) if range.is_valid() => Some(range),
+            _ => return Err(format!("Device '{}' has a bad target_range", key)),
+        },
+        None => None,
+    };
+    Ok(LocatedDevice {
+        device,
+        ip: ip.to_string(),
+        online: true,
+        target_range,
+    })
 }
@@ -0,0 +1,255 @@
+//! Finds device nodes on the local network, first by browsing for nodes that advertise
+//! themselves over mDNS/DNS-SD, then by asking every other host for its `/devices`.
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bluer::Uuid;
+use futures::{stream, StreamExt};
+use ipnet::Ipv4Net;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{sleep, timeout};
+
+use crate::devices::{self, LocatedDevice};
+use crate::periodic;
+use crate::registry::{DeviceRegistry, MergeSummary};
+use crate::thread_sharing::SharedConfig;
+
+/// The DNS-SD service type that nodes advertise themselves with
+pub const NODE_SERVICE_TYPE: &str = "_vannode._tcp.local.";
+/// How long to listen for advertised nodes before moving on
+const BROWSE_DURATION: Duration = Duration::from_secs(3);
+/// How many hosts get probed at once
+const PROBE_CONCURRENCY: usize = 64;
+/// How long a host has to answer before it's skipped
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Interfaces on bigger networks than this only get their own /24 searched
+const MIN_PREFIX_LEN: u8 = 24;
+/// The biggest network that can be configured to be searched, a /20 is 4094 hosts
+pub const MIN_SUBNET_PREFIX_LEN: u8 = 20;
+/// The most hosts a single search probes, however the subnets add up
+const MAX_HOSTS: usize = 1 << (32 - MIN_SUBNET_PREFIX_LEN);
+
+/// Finds the IPv4 networks of every interface that isn't a loopback
+pub fn local_subnets() -> Vec<Ipv4Net> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            eprintln!("Couldn't list the network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut subnets = BTreeSet::new();
+    for interface in interfaces.iter().filter(|i| !i.is_loopback()) {
+        if let if_addrs::IfAddr::V4(ref addr) = interface.addr {
+            let subnet = match Ipv4Net::with_netmask(addr.ip, addr.netmask) {
+                Ok(net) if net.prefix_len() >= MIN_PREFIX_LEN => net,
+                _ => Ipv4Net::new(addr.ip, MIN_PREFIX_LEN).unwrap(),
+            };
+            subnets.insert(subnet.trunc());
+        }
+    }
+    subnets.into_iter().collect()
+}
+
+/// Lists every host address in the subnets once, leaving out our own addresses and
+/// any that were already found, stopping at `MAX_HOSTS`
+fn hosts(subnets: &[Ipv4Net], skip: &[IpAddr]) -> Vec<Ipv4Addr> {
+    let own_addrs: Vec<IpAddr> = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces.iter().map(|i| i.ip()).collect(),
+        Err(_) => Vec::new(),
+    };
+    let mut hosts = BTreeSet::new();
+    for subnet in subnets {
+        for host in subnet.hosts() {
+            if hosts.len() >= MAX_HOSTS {
+                eprintln!("Only probing the first {} hosts of the subnets", MAX_HOSTS);
+                return hosts.into_iter().collect();
+            }
+            let addr = IpAddr::V4(host);
+            if !own_addrs.contains(&addr) && !skip.contains(&addr) {
+                hosts.insert(host);
+            }
+        }
+    }
+    hosts.into_iter().collect()
+}
+
+/// Listens for nodes advertising the service type
+///
+/// - 'service_type': the fully qualified DNS-SD type, e.g. `NODE_SERVICE_TYPE`
+/// - 'duration': how long to keep listening for answers
+///
+/// Returns one address and port for every resolved node, its lowest IPv4 address when it
+/// has any so the same node isn't found at a different address each time
+pub async fn browse_nodes(service_type: &str, duration: Duration) -> Vec<(IpAddr, u16)> {
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Couldn't start mDNS browsing: {}", e);
+            return Vec::new();
+        }
+    };
+    let receiver = match daemon.browse(service_type) {
+        Ok(receiver) => receiver,
+        Err(e) => {
+            eprintln!("Couldn't browse for {}: {}", service_type, e);
+            let _ = daemon.shutdown();
+            return Vec::new();
+        }
+    };
+
+    let mut nodes = HashMap::new();
+    let deadline = sleep(duration);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            event = receiver.recv_async() => match event {
+                Ok(ServiceEvent::ServiceResolved(info)) => {
+                    let addr = match info.get_addresses_v4().into_iter().min() {
+                        Some(v4) => Some(IpAddr::V4(*v4)),
+                        None => info.get_addresses().iter().min().copied(),
+                    };
+                    if let Some(addr) = addr {
+                        nodes.insert(info.get_fullname().to_string(), (addr, info.get_port()));
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            },
+        }
+    }
+    let _ = daemon.shutdown();
+    let nodes: BTreeSet<(IpAddr, u16)> = nodes.into_values().collect();
+    nodes.into_iter().collect()
+}
+
+/// Finds every node's devices, asking the advertised nodes first and then probing
+/// every other host on the subnets for nodes that don't advertise
+///
+/// - 'subnets': the networks to search, the local interfaces' networks are used when empty
+pub async fn discover(subnets: &[Ipv4Net]) -> HashMap<Uuid, LocatedDevice> {
+    let advertised = browse_nodes(NODE_SERVICE_TYPE, BROWSE_DURATION).await;
+    println!("Found {} advertised nodes", advertised.len());
+    let mut addresses: Vec<String> = advertised
+        .iter()
+        .map(|(addr, port)| match addr {
+            IpAddr::V4(v4) => format!("{}:{}", v4, port),
+            IpAddr::V6(v6) => format!("[{}]:{}", v6, port),
+        })
+        .collect();
+
+    let subnets = if subnets.is_empty() {
+        local_subnets()
+    } else {
+        subnets.to_vec()
+    };
+    let skip: Vec<IpAddr> = advertised.iter().map(|(addr, _)| *addr).collect();
+    let hosts = hosts(&subnets, &skip);
+    println!(
+        "Probing {} hosts on {}",
+        hosts.len(),
+        subnets
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+    addresses.extend(hosts.iter().map(|host| host.to_string()));
+
+    stream::iter(addresses)
+        .map(probe)
+        .buffer_unordered(PROBE_CONCURRENCY)
+        .filter_map(|found| async move { found })
+        .fold(HashMap::new(), |mut devices, found| async move {
+            devices.extend(found);
+            devices
+        })
+        .await
+}
+
+/// Re-runs discovery every `discovery.rediscover_interval` and merges what's found into the
+/// registry, so nodes that boot late, reboot or get a new address are picked up without a
+/// restart
+///
+/// The subnets are read from the shared config each time around, see `periodic::every`
+/// for how reloads affect the interval.
+pub async fn rediscover_forever(
+    registry: DeviceRegistry,
+    shared_config: Arc<Mutex<SharedConfig>>,
+    config_changes: watch::Receiver<u64>,
+) {
+    periodic::every(
+        &shared_config,
+        |hub| Duration::from_secs(hub.discovery.rediscover_interval),
+        config_changes,
+        || async {
+            rediscover_now(&registry, &shared_config).await;
+        },
+    )
+    .await
+}
+
+/// Runs discovery on the configured subnets right away and merges the results in
+pub async fn rediscover_now(
+    registry: &DeviceRegistry,
+    shared_config: &Arc<Mutex<SharedConfig>>,
+) -> MergeSummary {
+    let subnets = shared_config.lock().await.hub.discovery.subnets.clone();
+    let summary = registry.merge(discover(&subnets).await).await;
+    for uuid in summary.added.iter() {
+        println!("Found new device {}", uuid);
+    }
+    for uuid in summary.moved.iter() {
+        println!("Device {} moved to a new address", uuid);
+    }
+    for uuid in summary.back_online.iter() {
+        println!("Device {} is back online", uuid);
+    }
+    summary
+}
+
+/// Asks a single host for its devices, giving up after `PROBE_TIMEOUT`
+pub async fn probe(ip: String) -> Option<HashMap<Uuid, LocatedDevice>> {
+    timeout(PROBE_TIMEOUT, devices::get_node_devices(ip))
+        .await
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mdns_sd::ServiceInfo;
+
+    #[tokio::test]
+    #[ignore = "needs multicast on a real network interface"]
+    async fn browse_finds_a_local_responder() {
+        let service_type = "_vannodetest._tcp.local.";
+        let ip = if_addrs::get_if_addrs()
+            .unwrap()
+            .into_iter()
+            .find(|i| !i.is_loopback() && i.ip().is_ipv4())
+            .expect("no IPv4 interface to advertise on")
+            .ip();
+        let responder = ServiceDaemon::new().unwrap();
+        let info = ServiceInfo::new(
+            service_type,
+            "test-node",
+            "test-node.local.",
+            ip.to_string(),
+            8123,
+            None,
+        )
+        .unwrap();
+        responder.register(info).unwrap();
+
+        let nodes = browse_nodes(service_type, Duration::from_secs(3)).await;
+        let _ = responder.shutdown();
+
+        assert!(nodes.contains(&(ip, 8123)));
+    }
+}
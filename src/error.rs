@@ -0,0 +1,112 @@
+//! The one error type for everything that can go wrong handling a request, whether it
+//! came in over HTTP, BLE or the control socket.
+//!
+//! Each of those turns it into its own kind of answer, an HTTP status, an ATT error or
+//! a control reply, so callers find out what went wrong instead of the hub panicking.
+use std::fmt;
+
+use bluer::Uuid;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum HubError {
+    /// The request itself couldn't be understood
+    InvalidCommand { reason: String },
+    /// Nothing goes by the name that was given
+    UnknownName { name: String },
+    /// The name is about as close to several devices, so none was picked
+    AmbiguousName {
+        name: String,
+        candidates: Vec<String>,
+    },
+    /// No located device has the given uuid
+    UnknownDevice { uuid: Uuid },
+    /// The device can't be set to the target
+    TargetOutOfRange {
+        name: String,
+        target: usize,
+        min: usize,
+        max: usize,
+    },
+    /// The device's node has stopped answering health checks, so it wasn't tried
+    DeviceOffline { name: String, ip: String },
+    /// The node couldn't be reached or didn't answer in time
+    NodeUnreachable { ip: String, reason: String },
+    /// The node answered but refused the action
+    NodeRejected {
+        ip: String,
+        status: u16,
+        reason: String,
+    },
+    /// The node answered with something that isn't what was asked for
+    InvalidNodeResponse { ip: String, reason: String },
+    /// The hub gave up waiting on the result
+    Timeout,
+    /// The business logic isn't taking requests anymore
+    HubUnavailable { reason: String },
+}
+
+impl HubError {
+    pub fn invalid_command(reason: impl Into<String>) -> HubError {
+        HubError::InvalidCommand {
+            reason: reason.into(),
+        }
+    }
+
+    /// Whether the request was at fault rather than the hub or a node
+    pub fn is_bad_request(&self) -> bool {
+        use HubError::*;
+        matches!(
+            self,
+            InvalidCommand { .. }
+                | UnknownName { .. }
+                | AmbiguousName { .. }
+                | UnknownDevice { .. }
+                | TargetOutOfRange { .. }
+        )
+    }
+}
+
+impl fmt::Display for HubError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use HubError::*;
+        match self {
+            InvalidCommand { reason } => write!(f, "{}", reason),
+            UnknownName { name } => write!(f, "No device or group named '{}'", name),
+            AmbiguousName { name, candidates } => write!(
+                f,
+                "'{}' could be any of {}, say which",
+                name,
+                candidates.join(", ")
+            ),
+            TargetOutOfRange {
+                name,
+                target,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} can't be set to {}, it takes {} though {}",
+                name, target, min, max
+            ),
+            UnknownDevice { uuid } => write!(f, "No device found with uuid {}", uuid),
+            DeviceOffline { name, ip } => {
+                write!(f, "{} is offline, its node at {} isn't answering", name, ip)
+            }
+            NodeUnreachable { ip, reason } => {
+                write!(f, "Couldn't reach the node at {}: {}", ip, reason)
+            }
+            NodeRejected { ip, status, reason } => write!(
+                f,
+                "The node at {} rejected the action ({}): {}",
+                ip, status, reason
+            ),
+            InvalidNodeResponse { ip, reason } => {
+                write!(f, "The node at {} answered strangely: {}", ip, reason)
+            }
+            Timeout => write!(f, "Timed out waiting for the node"),
+            HubUnavailable { reason } => write!(f, "{}", reason),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Groups of devices that one command goes to: one for each type of device, the rooms
+//! set up in the config, and every device at once.
+//!
+//! Groups have uuids like devices do, so they can be named in commands, sent commands
+//! over HTTP and get their own Bluetooth service.
+use std::sync::Arc;
+
+use bluer::Uuid;
+use device::{DeviceType, DEVICE_TYPES};
+use tokio::sync::Mutex;
+
+use crate::config::{normalize_name, HubConfig};
+use crate::devices::LocatedDevice;
+use crate::registry::DeviceRegistry;
+use crate::thread_sharing::SharedConfig;
+
+/// The group of every device
+pub const ALL_UUID: Uuid = Uuid::from_u128(0x3f6c1d8e2b7a4e59a1c4d7e0b9f25a13);
+/// Rooms get uuids made from their names under this prefix, so they're the same every run
+const ROOM_UUID_PREFIX: u128 = 0x6a1f0c3e_9d42_4b7e_0000_000000000000;
+/// What the group of every device goes by
+const ALL_NAMES: [&str; 2] = ["all", "everything"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupKind {
+    /// Every device of the type
+    Type(DeviceType),
+    /// The devices named in the config's `[rooms]`
+    Room(Vec<String>),
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    pub uuid: Uuid,
+    pub kind: GroupKind,
+}
+
+impl Group {
+    /// Whether the device belongs to the group
+    pub fn contains(&self, located_device: &LocatedDevice) -> bool {
+        match &self.kind {
+            GroupKind::Type(device_type) => located_device.device.device_type == Some(*device_type),
+            GroupKind::Room(members) => {
+                let name = normalize_name(&located_device.device.name);
+                members.iter().any(|member| normalize_name(member) == name)
+            }
+            GroupKind::All => true,
+        }
+    }
+
+    /// Every name the group can be called in a command, e.g. "lights" and "all lights"
+    fn names(&self) -> Vec<String> {
+        match self.kind {
+            GroupKind::Type(_) => vec![self.name.clone(), format!("all {}", self.name)],
+            GroupKind::Room(_) => vec![self.name.clone()],
+            GroupKind::All => ALL_NAMES.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+}
+
+/// Every group there is with the config as it stands
+pub fn groups(config: &HubConfig) -> Vec<Group> {
+    let mut groups: Vec<Group> = DEVICE_TYPES
+        .iter()
+        .map(|(device_type, name, u)| Group {
+            name: name.to_string(),
+            uuid: Uuid::from_u128(*u),
+            kind: GroupKind::Type(*device_type),
+        })
+        .collect();
+    let mut rooms: Vec<(&String, &Vec<String>)> = config.rooms.iter().collect();
+    rooms.sort();
+    for (name, members) in rooms {
+        groups.push(Group {
+            name: normalize_name(name),
+            uuid: room_uuid(name),
+            kind: GroupKind::Room(members.clone()),
+        });
+    }
+    groups.push(Group {
+        name: ALL_NAMES[0].to_string(),
+        uuid: ALL_UUID,
+        kind: GroupKind::All,
+    });
+    groups
+}
+
+/// The group with the uuid, if it's a group's at all
+pub fn find(config: &HubConfig, uuid: &Uuid) -> Option<Group> {
+    groups(config).into_iter().find(|group| &group.uuid == uuid)
+}
+
+/// The names the built in groups go by, which rooms can't take
+pub fn builtin_names() -> Vec<String> {
+    groups(&HubConfig::default())
+        .iter()
+        .flat_map(|group| group.names())
+        .collect()
+}
+
+/// The uuid a room always has, made from its name
+pub fn room_uuid(name: &str) -> Uuid {
+    // FNV-1a, which unlike the std hasher comes out the same on every build
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in normalize_name(name).bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    Uuid::from_u128(ROOM_UUID_PREFIX | hash as u128)
+}
+
+/// Every name a command can use: the devices, the groups and their aliases
+pub async fn command_names(
+    registry: &DeviceRegistry,
+    shared_config: &Arc<Mutex<SharedConfig>>,
+) -> Vec<(String, Uuid)> {
+    let mut names = registry.names().await;
+    let shared_config = shared_config.lock().await;
+    for group in groups(&shared_config.hub) {
+        for name in group.names() {
+            names.push((name, group.uuid));
+        }
+    }
+    shared_config.hub.with_aliases(names)
+}
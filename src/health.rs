@@ -0,0 +1,309 @@
+//! Checks on every node every `health.interval` by asking it for its `/devices`, so the
+//! hub knows which devices can be reached before anything is sent to them.
+//!
+//! A device is online while its node answers in time and lists it, degraded while the
+//! node answers slowly or has failed fewer than `health.offline_after` checks in a row,
+//! and offline after that. Offline devices are marked so in the registry, which takes
+//! them off Bluetooth and has commands for them turned away straight away instead of
+//! waiting on a node that isn't there.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bluer::Uuid;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio::time::timeout;
+
+use crate::config::HealthConfig;
+use crate::devices::{self, LocatedDevice};
+use crate::periodic;
+use crate::registry::DeviceRegistry;
+use crate::thread_sharing::{HubEvent, HubEvents, SharedConfig};
+
+/// How many nodes get checked at once
+const CHECK_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthState {
+    Online,
+    Degraded,
+    Offline,
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthState::Online => write!(f, "online"),
+            HealthState::Degraded => write!(f, "degraded"),
+            HealthState::Offline => write!(f, "offline"),
+        }
+    }
+}
+
+/// How a device's node has been doing, as reported over HTTP and the control socket
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceHealth {
+    pub uuid: Uuid,
+    pub name: String,
+    pub ip: String,
+    pub state: HealthState,
+    /// When the node last answered for the device, in seconds since the Unix epoch
+    pub last_seen: Option<u64>,
+    /// How many checks in a row the device has failed
+    pub failures: u32,
+    /// How long the node took to answer the last check it answered
+    pub response_ms: Option<u64>,
+}
+
+/// How a single check of a device went
+#[derive(Debug, Clone, Copy)]
+enum Outcome {
+    /// The node listed the device, after this long
+    Answered(Duration),
+    Failed,
+}
+
+/// What the checks have found out about a device so far
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    state: HealthState,
+    last_seen: Option<SystemTime>,
+    failures: u32,
+    response: Option<Duration>,
+}
+
+impl Record {
+    /// What a device the checks haven't got to yet is taken to be, going by the registry
+    fn unchecked(located_device: &LocatedDevice) -> Record {
+        Record {
+            state: match located_device.online {
+                true => HealthState::Online,
+                false => HealthState::Offline,
+            },
+            last_seen: None,
+            failures: 0,
+            response: None,
+        }
+    }
+
+    /// Takes in how the latest check went
+    fn record(&mut self, outcome: Outcome, config: &HealthConfig) {
+        match outcome {
+            Outcome::Answered(response) => {
+                self.failures = 0;
+                self.last_seen = Some(SystemTime::now());
+                self.response = Some(response);
+                self.state = match response >= Duration::from_millis(config.slow_ms) {
+                    true => HealthState::Degraded,
+                    false => HealthState::Online,
+                };
+            }
+            Outcome::Failed => {
+                self.failures = self.failures.saturating_add(1);
+                // A device that's already offline doesn't come back by failing again
+                self.state = match self.state {
+                    HealthState::Offline => HealthState::Offline,
+                    _ if self.failures >= config.offline_after => HealthState::Offline,
+                    _ => HealthState::Degraded,
+                };
+            }
+        }
+    }
+}
+
+/// A cheap to clone handle on what the health checks have found
+#[derive(Debug, Clone)]
+pub struct HealthMonitor {
+    records: Arc<RwLock<HashMap<Uuid, Record>>>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> HealthMonitor {
+        HealthMonitor {
+            records: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// How every device in the registry is doing, by name
+    pub async fn report(&self, registry: &DeviceRegistry) -> Vec<DeviceHealth> {
+        let records = self.records.read().await;
+        let mut report: Vec<DeviceHealth> = registry
+            .snapshot()
+            .await
+            .into_values()
+            .map(|located_device| {
+                let record = match records.get(&located_device.device.uuid) {
+                    Some(record) => *record,
+                    None => Record::unchecked(&located_device),
+                };
+                DeviceHealth {
+                    uuid: located_device.device.uuid,
+                    name: located_device.device.name,
+                    ip: located_device.ip,
+                    state: record.state,
+                    last_seen: record
+                        .last_seen
+                        .and_then(|seen| seen.duration_since(UNIX_EPOCH).ok())
+                        .map(|since_epoch| since_epoch.as_secs()),
+                    failures: record.failures,
+                    response_ms: record.response.map(|response| response.as_millis() as u64),
+                }
+            })
+            .collect();
+        report.sort_by(|a, b| a.name.cmp(&b.name));
+        report
+    }
+
+    /// Takes in how the latest check of the device went, returning its state before
+    /// and after
+    async fn record(
+        &self,
+        located_device: &LocatedDevice,
+        outcome: Outcome,
+        config: &HealthConfig,
+    ) -> (HealthState, HealthState) {
+        let mut records = self.records.write().await;
+        let record = records
+            .entry(located_device.device.uuid)
+            .or_insert_with(|| Record::unchecked(located_device));
+        let before = record.state;
+        record.record(outcome, config);
+        (before, record.state)
+    }
+}
+
+/// Checks on every node every `health.interval`
+///
+/// The settings are read from the shared config each time around, see `periodic::every`
+/// for how reloads affect the interval.
+pub async fn monitor_forever(
+    health: HealthMonitor,
+    registry: DeviceRegistry,
+    events: HubEvents,
+    shared_config: Arc<Mutex<SharedConfig>>,
+    config_changes: watch::Receiver<u64>,
+) {
+    let check = || async {
+        let config = shared_config.lock().await.hub.health;
+        check_now(&health, &registry, &events, &config).await;
+    };
+    check().await;
+    periodic::every(
+        &shared_config,
+        |hub| Duration::from_secs(hub.health.interval),
+        config_changes,
+        check,
+    )
+    .await
+}
+
+/// Checks on every node right away, once for all of its devices, and marks the devices
+/// whose state changed
+pub async fn check_now(
+    health: &HealthMonitor,
+    registry: &DeviceRegistry,
+    events: &HubEvents,
+    config: &HealthConfig,
+) {
+    let mut nodes: HashMap<String, Vec<LocatedDevice>> = HashMap::new();
+    for located_device in registry.snapshot().await.into_values() {
+        nodes
+            .entry(located_device.ip.clone())
+            .or_default()
+            .push(located_device);
+    }
+    let check_timeout = Duration::from_millis(config.timeout_ms);
+    let changes: Vec<(LocatedDevice, HealthState, HealthState)> = stream::iter(nodes)
+        .map(|(ip, located_devices)| async move {
+            let started = Instant::now();
+            let answer = timeout(check_timeout, devices::get_node_devices(ip)).await;
+            let response = started.elapsed();
+            let mut changes = Vec::new();
+            for located_device in located_devices {
+                let outcome = match &answer {
+                    Ok(Some(found)) if found.contains_key(&located_device.device.uuid) => {
+                        Outcome::Answered(response)
+                    }
+                    _ => Outcome::Failed,
+                };
+                let (before, after) = health.record(&located_device, outcome, config).await;
+                changes.push((located_device, before, after));
+            }
+            changes
+        })
+        .buffer_unordered(CHECK_CONCURRENCY)
+        .concat()
+        .await;
+
+    for (located_device, before, after) in changes {
+        let uuid = located_device.device.uuid;
+        // The registry can disagree even when the state didn't change, e.g. after a node
+        // that's offline registers again, so it's always brought in line
+        registry
+            .set_online(&uuid, after != HealthState::Offline)
+            .await;
+        if before == after {
+            continue;
+        }
+        println!(
+            "{} is {}, was {}",
+            located_device.device.name, after, before
+        );
+        match (before, after) {
+            (_, HealthState::Degraded) => events.publish(HubEvent::DeviceDegraded { uuid }),
+            // Coming back from offline the registry already said so
+            (HealthState::Degraded, HealthState::Online) => {
+                events.publish(HubEvent::DeviceOnline { uuid })
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_how_checks_went() {
+        use HealthState::*;
+        let fast = Outcome::Answered(Duration::from_millis(20));
+        let slow = Outcome::Answered(Duration::from_millis(1500));
+        let just_slow = Outcome::Answered(Duration::from_millis(1000));
+        let failed = Outcome::Failed;
+        // Where the device starts, what the checks found, where it ends up and how many
+        // checks in a row it has failed by then
+        let cases: [(HealthState, &[Outcome], HealthState, u32); 12] = [
+            (Online, &[fast], Online, 0),
+            (Online, &[slow], Degraded, 0),
+            (Online, &[just_slow], Degraded, 0),
+            (Degraded, &[fast], Online, 0),
+            (Online, &[failed], Degraded, 1),
+            (Online, &[failed, failed], Degraded, 2),
+            (Online, &[failed, failed, failed], Offline, 3),
+            (Online, &[failed, failed, failed, failed], Offline, 4),
+            (Online, &[failed, failed, fast, failed, failed], Degraded, 2),
+            (Offline, &[failed], Offline, 1),
+            (Offline, &[fast], Online, 0),
+            (Offline, &[slow], Degraded, 0),
+        ];
+        let config = HealthConfig::default();
+        for (start, outcomes, state, failures) in cases {
+            let mut record = Record {
+                state: start,
+                last_seen: None,
+                failures: 0,
+                response: None,
+            };
+            for outcome in outcomes {
+                record.record(*outcome, &config);
+            }
+            assert_eq!(record.state, state, "{} after {:?}", start, outcomes);
+            assert_eq!(record.failures, failures, "{} after {:?}", start, outcomes);
+        }
+    }
+}
@@ -1,25 +1,33 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{middleware, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::{dev::Server, middleware, web, App, HttpRequest, HttpResponse, HttpServer};
 use bluer::Uuid;
-use tokio::{main, spawn, sync::Mutex};
+use serde::Deserialize;
+use tokio::{main, spawn, sync::Mutex, time::timeout};
 
 use device::{Action, Device};
 //mod crate::shared_request;
 //use crate::devices::DEVICES;
-use crate::thread_sharing::{SharedConfig, SharedGetRequest};
+use crate::command_parser;
+use crate::devices;
+use crate::error::HubError;
+use crate::groups;
+use crate::health::HealthMonitor;
+use crate::registry::DeviceRegistry;
+use crate::shadow::{self, DeviceShadow};
+use crate::shutdown::{Shutdown, SHUTDOWN_TIMEOUT};
+use crate::thread_sharing::{CommandBus, HubRequest, HubResponse, SharedConfig};
+
+/// How long a caller waits on the node before getting a timeout back
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
 
 async fn index(
     req: HttpRequest,
     _shared_config_clone: web::Data<Arc<Mutex<SharedConfig>>>,
-    shared_request_clone: web::Data<Arc<Mutex<SharedGetRequest>>>,
 ) -> &'static str {
     println!("REQ: {req:?}");
-    {
-        let mut shared_request = shared_request_clone.lock().await;
-        *shared_request = SharedGetRequest::NoUpdate;
-    }
     "Nothing here!"
 }
 
@@ -28,29 +36,25 @@ async fn parsed_command(
     _req: HttpRequest,
     info: web::Query<HashMap<String, String>>,
     _shared_config_clone: web::Data<Arc<Mutex<SharedConfig>>>,
-    shared_request_clone: web::Data<Arc<Mutex<SharedGetRequest>>>,
-    _devices: web::Data<Vec<(String, Uuid)>>,
+    command_bus: web::Data<CommandBus>,
+    _registry: web::Data<DeviceRegistry>,
 ) -> HttpResponse {
-    let uuid = match info.get("uuid") {
-        Some(u) => match format!("0x{}", u.replace("-", "")).parse::<u128>() {
-            Ok(numb) => Uuid::from_u128(numb),
-            Err(_) => return HttpResponse::Ok().body("Bad Uuid given"),
-        },
-        None => return HttpResponse::Ok().body("Oops, we didn't get the Uuid"),
+    let uuid = match query_uuid(&info) {
+        Ok(uuid) => uuid,
+        Err(e) => return error_response(&e),
     };
 
+    // Whether the device takes the target is checked once it's known which device it is
     let target: Option<usize> = match info.get("target") {
         Some(t) => {
             if t.as_str() != "" {
                 match t.parse::<usize>() {
-                    Ok(n) => {
-                        if n < 8 {
-                            Some(n)
-                        } else {
-                            return HttpResponse::Ok().body("Oops, Target should be 0 <= t < 8");
-                        }
+                    Ok(n) => Some(n),
+                    Err(_) => {
+                        return error_response(&HubError::invalid_command(
+                            "Oops, Target should be a whole number",
+                        ))
                     }
-                    Err(_) => return HttpResponse::Ok().body("Oops, Target should be 0 <= t < 8"),
                 }
             } else {
                 None
@@ -61,137 +65,231 @@ async fn parsed_command(
 
     let action = match info.get("action") {
         Some(a) => a.to_lowercase(),
-        None => return HttpResponse::Ok().body("Oops, we didn't get the Action"),
+        None => {
+            return error_response(&HubError::invalid_command("Oops, we didn't get the Action"))
+        }
     };
 
     let action = match Action::from_str(action.as_str(), target) {
         Ok(a) => a,
-        Err(_) => return HttpResponse::Ok().body("Action wasn't a valid action."),
+        Err(_) => {
+            return error_response(&HubError::invalid_command("Action wasn't a valid action."))
+        }
     };
 
-    let result = {
-        let mut shared_request = shared_request_clone.lock().await;
-        *shared_request = SharedGetRequest::Command {
+    submit_command(
+        &command_bus,
+        HubRequest::Command {
             device_uuid: uuid,
-            action: action,
-        };
-        serde_json::to_string(&(*shared_request)).unwrap()
+            action,
+        },
+    )
+    .await
+}
+
+/// Reads the `uuid` a request is about
+fn query_uuid(info: &HashMap<String, String>) -> Result<Uuid, HubError> {
+    match info.get("uuid") {
+        Some(u) => match Uuid::parse_str(u.trim()) {
+            Ok(uuid) => Ok(uuid),
+            Err(_) => Err(HubError::invalid_command("Bad Uuid given")),
+        },
+        None => Err(HubError::invalid_command("Oops, we didn't get the Uuid")),
+    }
+}
+
+/// The device's last known state and how old it is, or its node's answer when `refresh`
+/// is set, e.g. `/status?uuid=<uuid>&refresh=true`
+async fn status(
+    info: web::Query<HashMap<String, String>>,
+    command_bus: web::Data<CommandBus>,
+    shadow: web::Data<DeviceShadow>,
+) -> HttpResponse {
+    let uuid = match query_uuid(&info) {
+        Ok(uuid) => uuid,
+        Err(e) => return error_response(&e),
+    };
+    let refresh = match info.get("refresh").map(|r| r.to_lowercase()) {
+        None => false,
+        Some(r) if r == "true" || r == "1" => true,
+        Some(r) if r == "false" || r == "0" => false,
+        Some(_) => {
+            return error_response(&HubError::invalid_command(
+                "Oops, refresh should be true or false",
+            ))
+        }
     };
-    HttpResponse::Ok().body(result)
+    let read = shadow::read(&shadow, &command_bus, uuid, refresh);
+    let result = match timeout(COMMAND_TIMEOUT, read).await {
+        Ok(result) => result,
+        Err(_) => Err(HubError::Timeout),
+    };
+    match result {
+        Ok(state) => HttpResponse::Ok().json(state),
+        Err(e) => error_response(&e),
+    }
+}
+
+/// Whether every device's node is online, degraded or offline and when it last answered
+async fn health_report(
+    health: web::Data<HealthMonitor>,
+    registry: web::Data<DeviceRegistry>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(health.report(&registry).await)
 }
 
 async fn command(
     _req: HttpRequest,
     info: web::Query<HashMap<String, String>>,
-    _shared_config_clone: web::Data<Arc<Mutex<SharedConfig>>>,
-    shared_request_clone: web::Data<Arc<Mutex<SharedGetRequest>>>,
-    devices: web::Data<Vec<(String, Uuid)>>,
+    shared_config_clone: web::Data<Arc<Mutex<SharedConfig>>>,
+    command_bus: web::Data<CommandBus>,
+    registry: web::Data<DeviceRegistry>,
 ) -> HttpResponse {
     let command = match info.get("command") {
         Some(i) => i,
-        None => return HttpResponse::Ok().body("Oops, we didn't get the command"),
+        None => {
+            return error_response(&HubError::invalid_command(
+                "Oops, we didn't get the command",
+            ))
+        }
     };
 
-    dbg!(&info);
-    let mut device = String::new();
-    let command = command.replace("%20", " ").to_lowercase();
-    let mut command = command.split_whitespace();
-    while devices
-        .clone()
-        .iter()
-        .map(|(n, _)| n)
-        .collect::<Vec<&String>>()
-        .contains(&&device)
-    {
-        //while !DEVICES.contains_key(&device.as_str()) {
-        let word = match command.next() {
-            Some(w) => w,
-            None => return HttpResponse::Ok().body("Oops, we didn't get a device!"),
-        };
-
-        if device.is_empty() {
-            device = word.to_string();
-        } else {
-            device = format!("{} {}", &device, &word);
-        }
-    }
+    log::debug!("Parsing command {:?}", command);
+    let devices = groups::command_names(&registry, &shared_config_clone).await;
+    let matching = shared_config_clone.lock().await.hub.matching;
+    let command = command.replace("%20", " ");
+    let parsed = match command_parser::parse(&command, &devices, &matching) {
+        Ok(parsed) => parsed,
+        Err(e) => return error_response(&e.into()),
+    };
 
-    let mut uuid = Uuid::from_u128(0x0);
-    for (n, u) in devices.iter() {
-        if &device == n {
-            uuid = u.clone();
-            break;
-        }
-    }
-    if uuid.as_u128() == 0x0 {
-        return HttpResponse::Ok().body("Couldn't get the uuid of the named device.");
-    }
+    submit_command(&command_bus, parsed.request()).await
+}
 
-    let action = match command.next() {
-        Some(a) => a,
-        None => return HttpResponse::Ok().body("Oops, we didn't get an action!"),
-    };
+/// What a node sends to announce itself
+#[derive(Debug, Deserialize)]
+struct NodeRegistration {
+    /// Where the node can be reached, e.g. "192.168.1.20:80", defaults to where the
+    /// request came from
+    address: Option<String>,
+    /// The same payload the node serves on `/devices`
+    devices: serde_json::Value,
+}
 
-    let target = match command.next() {
-        Some(t) => {
-            if t.is_empty() {
-                None
-            } else {
-                match t.parse::<usize>() {
-                    Ok(n) => {
-                        if n < 8 {
-                            Some(n)
-                        } else {
-                            return HttpResponse::Ok().body("Oops, Target should be 0 <= t < 8");
-                        }
-                    }
-                    Err(_) => {
-                        return HttpResponse::Ok()
-                            .body("Oops, Target should be a number, 0 though 7")
-                    }
-                }
+/// Lets a node announce itself and its devices instead of waiting to be discovered
+async fn register(
+    req: HttpRequest,
+    registration: web::Json<NodeRegistration>,
+    registry: web::Data<DeviceRegistry>,
+) -> HttpResponse {
+    let address = match &registration.address {
+        Some(address) if !address.trim().is_empty() => address.trim().to_string(),
+        _ => match req.peer_addr() {
+            Some(peer) => peer.ip().to_string(),
+            None => {
+                return error_response(&HubError::invalid_command(
+                    "Oops, we didn't get the address",
+                ))
             }
+        },
+    };
+
+    // A node registering itself is told about every bad device rather than having some
+    // quietly left out
+    let located_devices = match devices::parse_node_devices(&address, &registration.devices) {
+        Ok((located_devices, problems)) if problems.is_empty() => located_devices,
+        Ok((_, problems)) => {
+            return error_response(&HubError::invalid_command(format!(
+                "Oops, {}",
+                problems.join(", ")
+            )))
         }
-        None => None,
+        Err(e) => return error_response(&HubError::invalid_command(format!("Oops, {}", e))),
     };
+    let registered: Vec<Uuid> = located_devices.keys().cloned().collect();
+    let summary = registry.register(located_devices).await;
+    println!(
+        "Node at {} registered {} devices ({} new)",
+        &address,
+        registered.len(),
+        summary.added.len()
+    );
+    HttpResponse::Ok().json(serde_json::json!({ "registered": registered }))
+}
 
-    let action = match Action::from_str(action, target) {
-        Ok(a) => a,
-        Err(_) => return HttpResponse::Ok().body("Action wasn't a valid action."),
+/// Puts the command on the bus and waits for the node's answer, returning the
+/// device's resulting state or what went wrong
+async fn submit_command(command_bus: &CommandBus, request: HubRequest) -> HttpResponse {
+    let result = match timeout(COMMAND_TIMEOUT, command_bus.submit(request)).await {
+        Ok(result) => result,
+        Err(_) => Err(HubError::Timeout),
     };
+    match result {
+        Ok(HubResponse::Device { device }) => HttpResponse::Ok().json(device),
+        Ok(HubResponse::Group { results }) => HttpResponse::Ok().json(results),
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => error_response(&e),
+    }
+}
 
-    let result = {
-        let mut shared_request = shared_request_clone.lock().await;
-        *shared_request = SharedGetRequest::Command {
-            device_uuid: uuid,
-            action: action,
-        };
-        serde_json::to_string(&(*shared_request)).unwrap()
+/// Picks the status code that best describes the error and sends it along as json
+fn error_response(error: &HubError) -> HttpResponse {
+    use HubError::*;
+    let mut response = match error {
+        InvalidCommand { .. } | TargetOutOfRange { .. } => HttpResponse::BadRequest(),
+        UnknownName { .. } | UnknownDevice { .. } => HttpResponse::NotFound(),
+        NodeUnreachable { .. } | InvalidNodeResponse { .. } => HttpResponse::BadGateway(),
+        AmbiguousName { .. } => HttpResponse::Conflict(),
+        NodeRejected { .. } => HttpResponse::UnprocessableEntity(),
+        Timeout => HttpResponse::GatewayTimeout(),
+        DeviceOffline { .. } | HubUnavailable { .. } => HttpResponse::ServiceUnavailable(),
     };
-    HttpResponse::Ok().body(result)
+    let mut body = serde_json::to_value(error).unwrap();
+    body["message"] = serde_json::Value::String(error.to_string());
+    response.json(body)
 }
 
-pub async fn run_http_server(
+/// Binds the HTTP API to `http.listen_addr`, returning the server to run once that
+/// worked
+pub async fn bind_http_server(
     shared_config_clone: Arc<Mutex<SharedConfig>>,
-    shared_request_clone: Arc<Mutex<SharedGetRequest>>,
-    devices: Vec<(String, Uuid)>,
-) -> std::io::Result<()> {
+    command_bus: CommandBus,
+    registry: DeviceRegistry,
+    shadow: DeviceShadow,
+    health: HealthMonitor,
+    shutdown: Shutdown,
+) -> std::io::Result<Server> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    log::info!("starting HTTP server at http://localhost:8080");
+    let listen_addr = shared_config_clone.lock().await.hub.http.listen_addr;
+    log::info!("starting HTTP server at http://{}", listen_addr);
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             .app_data(web::Data::new(shared_config_clone.clone()))
-            .app_data(web::Data::new(shared_request_clone.clone()))
-            .app_data(web::Data::new(devices.clone()))
+            .app_data(web::Data::new(command_bus.clone()))
+            .app_data(web::Data::new(registry.clone()))
+            .app_data(web::Data::new(shadow.clone()))
+            .app_data(web::Data::new(health.clone()))
             .service(web::resource("/").to(index))
             .service(web::resource("/parsed_command").to(parsed_command))
             .service(web::resource("/command").to(command))
+            .service(web::resource("/status").to(status))
+            .service(web::resource("/health").to(health_report))
+            .service(web::resource("/register").route(web::post().to(register)))
     })
-    .bind(("0.0.0.0", 8080))
-    .unwrap()
-    .run()
-    .await
+    // The hub's shutdown decides when to stop, not actix's own signal handling
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs())
+    .bind(listen_addr)?
+    .run();
+
+    // Stop taking new requests once the hub shuts down, finishing the ones in flight
+    let handle = server.handle();
+    spawn(async move {
+        shutdown.triggered().await;
+        handle.stop(true).await;
+    });
+    Ok(server)
 }
@@ -1,48 +1,58 @@
 use clap::Command;
-use clap::{arg, Arg, ArgAction};
+use clap::{arg, Arg, ArgAction, ArgMatches};
 use std::collections::HashMap;
 use std::env;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{thread, time};
+use std::time;
 
 use bluer::Uuid;
-use device::{Action, Device, DeviceType, DEVICE_TYPES};
+use device::{Action, Device, DeviceType};
 use fs2::FileExt;
 use futures::future::join_all;
+use ipnet::Ipv4Net;
 use tokio::{
-    main, spawn,
+    main,
+    net::TcpListener,
+    signal::unix::{signal, SignalKind},
+    spawn,
     sync::{mpsc, Mutex},
     task,
 };
 
+mod ble_protocol;
 mod ble_server;
+mod command_parser;
+mod config;
+mod control;
 mod devices;
+mod discovery;
+mod error;
+mod groups;
+mod health;
 mod http_server;
+mod periodic;
+mod registry;
+mod shadow;
+mod shutdown;
 mod thread_sharing;
-use thread_sharing::{SharedBLEAction, SharedConfig, SharedGetRequest};
-
-const SHUTDOWN_COMMAND: &str = "shutdown";
-const LISTEN_ADDR: &str = "127.0.0.1:4000"; // Choose an appropriate address and port
-
-// Flag when the stream consists of the shutdown command
-async fn handle_client(mut stream: TcpStream, shutdown_flag: Arc<AtomicBool>) {
-    let mut buffer = [0; 1024];
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            let received = String::from_utf8_lossy(&buffer[..size]);
-            if received.trim() == SHUTDOWN_COMMAND {
-                shutdown_flag.store(true, Ordering::SeqCst);
-            }
-        }
-        Err(e) => eprintln!("Failed to receive data: {}", e),
-    }
-}
+use ble_server::BleContext;
+use command_parser::Change;
+use config::{ConfigOverrides, ConfigReloader, HubConfig};
+use control::{ControlClient, ControlContext, ControlEndpoint, SHUTDOWN_COMMAND, WATCH_COMMAND};
+use devices::{LocatedDevice, TargetRange};
+use error::HubError;
+use groups::Group;
+use health::HealthMonitor;
+use registry::DeviceRegistry;
+use shadow::DeviceShadow;
+use shutdown::{Shutdown, SHUTDOWN_TIMEOUT};
+use thread_sharing::{
+    BusMessage, CommandBus, HubEvent, HubEvents, HubRequest, HubResponse, HubResult, MemberResult,
+    SharedConfig,
+};
 
 #[tokio::main]
 async fn main() {
@@ -66,11 +76,91 @@ async fn main() {
                         .long("node-count")
                         .action(clap::ArgAction::Set)
                         .help("Set the number of nodes to look for."),
+                )
+                .arg(
+                    Arg::new("subnet")
+                        .short('s')
+                        .long("subnet")
+                        .action(clap::ArgAction::Append)
+                        .value_name("CIDR")
+                        .help("A network to search for nodes, e.g. 192.168.1.0/24."),
+                )
+                .arg(
+                    Arg::new("rediscover-interval")
+                        .short('r')
+                        .long("rediscover-interval")
+                        .action(clap::ArgAction::Set)
+                        .value_name("SECONDS")
+                        .help("How often to look for nodes that came, went or moved."),
+                )
+                .arg(
+                    Arg::new("state-file")
+                        .long("state-file")
+                        .action(clap::ArgAction::Set)
+                        .value_name("PATH")
+                        .help("Where to keep the last known devices between runs."),
                 ),
         )
         .subcommand(
             Command::new("shutdown").about("Shutdown's the program and it's it all down"), // ... additional settings or arguments specific to "run" ...
         )
+        .subcommand(Command::new("devices").about("Lists the devices the running hub knows about"))
+        .subcommand(
+            Command::new("status")
+                .about("Asks the running hub for a device's last known state")
+                .arg(
+                    Arg::new("device")
+                        .required(true)
+                        .num_args(1..)
+                        .help("The device's name or one of its aliases"),
+                )
+                .arg(
+                    Arg::new("refresh")
+                        .long("refresh")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Ask the device's node instead of going by what the hub last heard"),
+                ),
+        )
+        .subcommand(
+            Command::new("health")
+                .about("Shows which devices' nodes are answering and when they last did"),
+        )
+        .subcommand(
+            Command::new("send")
+                .about("Has the running hub send a command, e.g. \"kitchen light set 3\"")
+                .arg(
+                    Arg::new("command")
+                        .required(true)
+                        .num_args(1..)
+                        .help("<device> <action> [target]"),
+                ),
+        )
+        .subcommand(
+            Command::new(WATCH_COMMAND).about("Prints what happens to devices as it happens"),
+        )
+        .arg(
+            Arg::new("remote")
+                .long("remote")
+                .value_name("ADDR")
+                .global(true)
+                .help("Talk to a hub's TCP control address, e.g. 192.168.1.5:4000, instead of the local socket"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .global(true)
+                .action(clap::ArgAction::SetTrue)
+                .help("Print the hub's replies as JSON"),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .global(true)
+                .help(
+                    "The TOML config file to use, hub.toml when it exists otherwise the defaults",
+                ),
+        )
         .arg(
             Arg::new("log_level")
                 .long("log-level")
@@ -93,23 +183,41 @@ async fn main() {
 
     match command.subcommand() {
         Some(("run", sub_matches)) => {
-            // Spawn a thread to handle the TCP server that's userd for sending/receiving
-            // commands from other consoles
-            let shutdown_flag = Arc::new(AtomicBool::new(false));
-            let listener = TcpListener::bind(LISTEN_ADDR).expect("Failed to bind to address");
-            let shutdown_flag_clone = Arc::clone(&shutdown_flag);
+            let mut config = load_config(sub_matches);
+            let overrides = run_overrides(sub_matches);
+            overrides.apply(&mut config);
+
+            // Set up stuff that needs to be shared between threads
+            let shared_config = Arc::new(Mutex::new(SharedConfig {
+                verbosity: String::from("some"),
+                hub: config.clone(),
+            }));
+            let config_path = sub_matches.get_one::<String>("config").map(PathBuf::from);
+            let reloader = ConfigReloader::new(config_path, overrides, shared_config.clone());
+
+            // Reload the config whenever we get a SIGHUP
+            let reloader_clone = reloader.clone();
             tokio::spawn(async move {
-                for stream in listener.incoming() {
-                    match stream {
-                        Ok(stream) => {
-                            let shutdown_flag_clone = Arc::clone(&shutdown_flag_clone);
-                            handle_client(stream, shutdown_flag_clone).await;
-                        }
-                        Err(e) => eprintln!("Connection failed: {}", e),
+                let mut hangups = match signal(SignalKind::hangup()) {
+                    Ok(hangups) => hangups,
+                    Err(e) => {
+                        eprintln!("Can't listen for SIGHUP: {}", e);
+                        return;
+                    }
+                };
+                while hangups.recv().await.is_some() {
+                    match reloader_clone.reload().await {
+                        Ok(report) => println!("Reloaded the config, {}", report),
+                        Err(e) => eprintln!("Kept the old config. {}", e),
                     }
                 }
             });
 
+            // Everything winds down together on a shutdown command, SIGINT or SIGTERM
+            let shutdown = Shutdown::new();
+            tokio::spawn(shutdown::shutdown_on_signals(shutdown.clone()));
+            let (command_bus, bus_receiver) = CommandBus::new();
+
             // Get the current working directory
             let current_dir = match env::current_dir() {
                 Ok(dir) => dir,
@@ -120,7 +228,7 @@ async fn main() {
             };
 
             // Construct the path to the lock file
-            let lock_path = current_dir.join("hub_app.lock");
+            let lock_path = current_dir.join(&config.lock_file);
             let file = match File::create(&lock_path) {
                 Ok(file) => file,
                 Err(e) => {
@@ -135,83 +243,276 @@ async fn main() {
                 process::exit(1);
             }
 
-            // Set up stuff that needs to be shared between threads
-            let shared_config = Arc::new(Mutex::new(SharedConfig {
-                verbosity: String::from("some"),
-            }));
-            let shared_request = Arc::new(Mutex::new(SharedGetRequest::NoUpdate));
+            // Start out with the devices from last time, if there were any
+            let events = HubEvents::new();
+            let registry =
+                DeviceRegistry::load(current_dir.join(&config.state_file), events.clone());
+            let shadow = DeviceShadow::new();
+            let health = HealthMonitor::new();
+
+            // Now that there's a registry to manage, start answering admin commands from
+            // other consoles, on the Unix socket and over TCP when asked to
+            let control_context = ControlContext {
+                shutdown: shutdown.clone(),
+                command_bus: command_bus.clone(),
+                reloader: reloader.clone(),
+                registry: registry.clone(),
+                shadow: shadow.clone(),
+                health: health.clone(),
+                shared_config: shared_config.clone(),
+                events: events.clone(),
+            };
+            let socket_path = current_dir.join(&config.control.socket_path);
+            let unix_listener = match control::bind_unix_socket(&socket_path, &config.control) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to bind to {}: {}", socket_path.display(), e);
+                    process::exit(1);
+                }
+            };
+            tokio::spawn(control::run_unix_control_server(
+                unix_listener,
+                control_context.clone(),
+            ));
+            if let Some(listen_addr) = config.control.listen_addr {
+                let tcp_listener = match TcpListener::bind(listen_addr).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        eprintln!("Failed to bind to {}: {}", listen_addr, e);
+                        process::exit(1);
+                    }
+                };
+                tokio::spawn(control::run_tcp_control_server(
+                    tcp_listener,
+                    control_context,
+                ));
+            }
+
+            // Start the http server with the appropreate info passed in, before looking for
+            // nodes so that they can register themselves in the meantime
+            let shared_config_clone = shared_config.clone();
+            let command_bus_clone = command_bus.clone();
+            let registry_clone = registry.clone();
+            let shadow_clone = shadow.clone();
+            let health_clone = health.clone();
+            let shutdown_clone = shutdown.clone();
+            let http_server = match http_server::bind_http_server(
+                shared_config_clone,
+                command_bus_clone,
+                registry_clone,
+                shadow_clone,
+                health_clone,
+                shutdown_clone,
+            )
+            .await
+            {
+                Ok(server) => server,
+                Err(e) => {
+                    eprintln!("Failed to bind to {}: {}", config.http.listen_addr, e);
+                    process::exit(1);
+                }
+            };
+            let http_task = tokio::spawn(async move {
+                if let Err(e) = http_server.await {
+                    eprintln!("Http server stopped: {}", e);
+                }
+            });
+            println!("Http server started");
 
             // Get the list of connected devices if applicable
-            let mut located_devices = HashMap::new();
-            let node_count: Option<&String> = sub_matches.get_one("node-count");
+            let subnets = config.discovery.subnets.clone();
             if sub_matches.get_flag("no-nodes") {
                 println!("Skipping getting devices!");
+            } else if registry.len().await > 0 {
+                // Serve the cached devices right away and check on them in the background
+                println!("Using the last known devices while checking on them");
+                let registry_clone = registry.clone();
+                let subnets_clone = subnets.clone();
+                tokio::spawn(async move {
+                    registry_clone
+                        .merge(devices::get_devices(&subnets_clone).await)
+                        .await
+                });
             } else {
                 println!("Getting Devices!!");
-                match node_count {
-                    Some(nc) => {
-                        let nc: usize = nc.parse().unwrap();
-                        while located_devices.len() < nc && !shutdown_flag.load(Ordering::SeqCst) {
-                            thread::sleep(time::Duration::from_millis(10000));
-                            located_devices = devices::get_devices().await;
-                        }
+                let nc = config.discovery.node_count.unwrap_or(0);
+                loop {
+                    registry.merge(devices::get_devices(&subnets).await).await;
+                    if registry.len().await >= nc || shutdown.is_triggered() {
+                        break;
                     }
-                    None => {
-                        thread::sleep(time::Duration::from_millis(10000));
-                        located_devices = devices::get_devices().await;
+                    tokio::select! {
+                        _ = tokio::time::sleep(time::Duration::from_millis(10000)) => {}
+                        _ = shutdown.triggered() => {}
                     }
                 }
             }
 
             println!("Devices:");
-            for device in located_devices.keys() {
+            for device in registry.snapshot().await.keys() {
                 println!("    {}", &device);
             }
 
-            // Start the http server with the appropreate info passed in
+            // Keep looking for nodes that boot late, reboot or move
+            let registry_clone = registry.clone();
             let shared_config_clone = shared_config.clone();
-            let shared_request_clone = shared_request.clone();
-            let devices = located_devices
-                .iter()
-                .map(|(u, ld)| (ld.device.name.clone(), u.clone()))
-                .collect::<Vec<(String, Uuid)>>();
+            let config_changes = reloader.subscribe();
             tokio::spawn(async move {
-                http_server::run_http_server(shared_config_clone, shared_request_clone, devices)
+                discovery::rediscover_forever(registry_clone, shared_config_clone, config_changes)
                     .await
             });
-            println!("Http server started");
+
+            // Keep track of which nodes are answering, so nothing waits on the ones that aren't
+            tokio::spawn(health::monitor_forever(
+                health,
+                registry.clone(),
+                events.clone(),
+                shared_config.clone(),
+                reloader.subscribe(),
+            ));
+
+            // Keep the shadow of every device's state fresh, even when it's changed at the device
+            tokio::spawn(shadow::poll_forever(
+                shadow.clone(),
+                registry.clone(),
+                events.clone(),
+                shared_config.clone(),
+                reloader.subscribe(),
+            ));
 
             // Start the bluetooth server
-            let shared_ble_action = Arc::new(Mutex::new(thread_sharing::SharedBLEAction::NoUpdate));
-            let shared_ble_action_clone = shared_ble_action.clone();
-            let mut devices: Vec<(String, Uuid)> = located_devices
-                .iter()
-                .map(|(_, ld)| (ld.device.name.clone(), ld.device.uuid.clone()))
-                .collect();
-            for (_, n, u) in DEVICE_TYPES.iter() {
-                devices.push((n.to_string(), Uuid::from_u128(u.clone())));
-            }
-            tokio::spawn(async move {
-                ble_server::run_ble_server(shared_ble_action_clone, devices).await
-            });
+            let ble_context = BleContext {
+                shared_config: shared_config.clone(),
+                command_bus: command_bus.clone(),
+                registry: registry.clone(),
+                shadow: shadow.clone(),
+                events: events.clone(),
+            };
+            let ble_task = tokio::spawn(ble_server::run_ble_server(
+                ble_context,
+                reloader.subscribe(),
+                shutdown.clone(),
+            ));
 
             println!("Ble server started");
-            business_logic(
-                located_devices,
-                shutdown_flag.clone(),
-                shared_request.clone(),
-                shared_ble_action.clone(),
-            )
+            let business_task = tokio::spawn(business_logic(
+                shared_config.clone(),
+                registry,
+                shadow,
+                events,
+                bus_receiver,
+                shutdown.clone(),
+            ));
+
+            // Wait for the shutdown, then give the http server, the commands already
+            // submitted and the bluetooth server a bounded amount of time to finish up
+            shutdown.triggered().await;
+            println!("Shutting down");
+            let wound_down = tokio::time::timeout(SHUTDOWN_TIMEOUT, async {
+                let _ = http_task.await;
+                let _ = business_task.await;
+                let _ = ble_task.await;
+            })
             .await;
+            control::remove_unix_socket(&socket_path);
+            if let Err(e) = file.unlock() {
+                eprintln!("Failed to release the lock file: {}", e);
+            }
+            if wound_down.is_err() {
+                eprintln!(
+                    "Gave up waiting after {} seconds, exiting anyway",
+                    SHUTDOWN_TIMEOUT.as_secs()
+                );
+                process::exit(1);
+            }
             println!("Shutdown!!!!!!!!");
             process::exit(0);
         }
-        Some((SHUTDOWN_COMMAND, _sub_matches)) => {
+        Some((SHUTDOWN_COMMAND, sub_matches)) => {
             println!("Shutting down the program!!!");
-            let mut stream = TcpStream::connect("127.0.0.1:4000").unwrap();
-            stream.write_all(SHUTDOWN_COMMAND.as_bytes()).unwrap();
+            let endpoint = control_endpoint(sub_matches);
+            match control::send_control_command(&endpoint, SHUTDOWN_COMMAND).await {
+                Ok(reply) if reply.ok => {}
+                Ok(reply) => {
+                    eprintln!("{}", reply.error_message());
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            process::exit(0);
+        }
+        Some((name @ ("devices" | "status" | "health" | "send"), sub_matches)) => {
+            let endpoint = control_endpoint(sub_matches);
+            let json = sub_matches.get_flag("json");
+            let command = match name {
+                "status" if sub_matches.get_flag("refresh") => {
+                    format!("refresh {}", joined_words(sub_matches, "device"))
+                }
+                "status" => format!("status {}", joined_words(sub_matches, "device")),
+                "send" => format!("send {}", joined_words(sub_matches, "command")),
+                _ => name.to_string(),
+            };
+            let reply = match control::send_control_command(&endpoint, &command).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            if json {
+                println!("{}", serde_json::to_string(&reply).unwrap());
+            } else if let Some(result) = &reply.result {
+                println!("{}", control::describe_result(name, result));
+            }
+            if !reply.ok {
+                if !json {
+                    eprintln!("{}", reply.error_message());
+                }
+                process::exit(1);
+            }
             process::exit(0);
         }
+        Some((WATCH_COMMAND, sub_matches)) => {
+            let endpoint = control_endpoint(sub_matches);
+            let json = sub_matches.get_flag("json");
+            let mut client = match ControlClient::connect(&endpoint).await {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            };
+            match client.request(WATCH_COMMAND).await {
+                Ok(reply) if reply.ok => {}
+                Ok(reply) => {
+                    eprintln!("{}", reply.error_message());
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            loop {
+                match client.next_event().await {
+                    Ok(Some(event)) if json => {
+                        println!("{}", serde_json::to_string(&event).unwrap())
+                    }
+                    Ok(Some(event)) => println!("{}", event),
+                    Ok(None) => {
+                        eprintln!("The hub went away");
+                        process::exit(1);
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    }
+                }
+            }
+        }
         _ => {
             println!("You must enter a command, perhapse you wanted:");
             println!("  > hub run");
@@ -222,112 +523,388 @@ async fn main() {
     }
 }
 
-async fn business_logic(
-    mut located_devices: HashMap<Uuid, devices::LocatedDevice>,
-    shutdown_flag: Arc<AtomicBool>,
-    shared_get_request: Arc<Mutex<SharedGetRequest>>,
-    shared_ble_action: Arc<Mutex<SharedBLEAction>>,
-) {
-    let mut last_action = (Uuid::from_u128(0x0), Action::On);
-    while !shutdown_flag.load(Ordering::SeqCst) {
-        {
-            use SharedGetRequest::*;
-            let mut shared_request = shared_get_request.lock().await;
-            match &*shared_request {
-                Command {
-                    ref device_uuid,
-                    ref action,
-                } => {
-                    if last_action != (device_uuid.clone(), action.clone()) {
-                        last_action = (device_uuid.clone(), action.clone());
-                        let located_device = located_devices.get(&device_uuid);
-                        let target = match action.get_target() {
-                            Some(t) => t.to_string(),
-                            None => "".to_string(),
-                        };
-                        match located_device {
-                            Some(d) => {
-                                let url = format!(
-                                    "http://{}/command?uuid={}&action={}&target={}",
-                                    &d.ip,
-                                    &device_uuid,
-                                    &action.to_str().to_string(),
-                                    &target
-                                );
-                                reqwest::get(&url).await.unwrap();
-                                *shared_request = SharedGetRequest::NoUpdate;
-                            }
-                            None => {
-                                //println!("no device found");
-                            }
-                        }
-                    }
-                }
-                NoUpdate => {
-                    // println!("6666666666666 NoUpdate!!!");
-                }
+/// Reads the config given with `--config`, exiting with the problems if it can't be used
+fn load_config(matches: &ArgMatches) -> HubConfig {
+    let path = matches.get_one::<String>("config").map(PathBuf::from);
+    match HubConfig::load(path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Where the client subcommands find the hub, the configured Unix socket unless
+/// `--remote` points at a TCP control address
+fn control_endpoint(sub_matches: &ArgMatches) -> ControlEndpoint {
+    match sub_matches.get_one::<String>("remote") {
+        Some(addr) => match addr.parse() {
+            Ok(addr) => ControlEndpoint::Tcp(addr),
+            Err(e) => {
+                eprintln!("Bad remote address '{}': {}", addr, e);
+                process::exit(1);
+            }
+        },
+        None => ControlEndpoint::Unix(load_config(sub_matches).control.socket_path),
+    }
+}
+
+/// The words given for a multi-word argument, joined back up with spaces
+fn joined_words(sub_matches: &ArgMatches, id: &str) -> String {
+    match sub_matches.get_many::<String>(id) {
+        Some(words) => words.cloned().collect::<Vec<String>>().join(" "),
+        None => String::new(),
+    }
+}
+
+/// Gathers the flags given to `run` that take precedence over the config file
+fn run_overrides(sub_matches: &ArgMatches) -> ConfigOverrides {
+    let mut overrides = ConfigOverrides::default();
+    if let Some(nc) = sub_matches.get_one::<String>("node-count") {
+        match nc.parse() {
+            Ok(nc) => overrides.node_count = Some(nc),
+            Err(e) => {
+                eprintln!("Bad node count '{}': {}", nc, e);
+                process::exit(1);
             }
         }
-        {
-            use SharedBLEAction::*;
-            let mut shared_action = shared_ble_action.lock().await;
-            match &*shared_action {
-                Command {
-                    ref device_uuid,
-                    ref action,
-                } => {
-                    let mut already_processed = false;
-                    for (t, _, u) in DEVICE_TYPES.iter() {
-                        if device_uuid == &Uuid::from_u128(u.clone()) {
-                            for (u, ld) in located_devices.iter_mut() {
-                                match ld.device.device_type {
-                                    Some(t) => update_device(&ld.ip, &u, &action).await,
-                                    _ => {}
-                                }
-                            }
-                            already_processed = true;
-                        };
-                    }
-                    if !already_processed {
-                        let located_device = located_devices.get_mut(&device_uuid).unwrap();
-                        update_device(&located_device.ip, &device_uuid, &action).await;
-                    }
-                    *shared_action = NoUpdate;
+    }
+    if let Some(values) = sub_matches.get_many::<String>("subnet") {
+        let mut subnets = Vec::new();
+        for value in values {
+            match value.parse::<Ipv4Net>() {
+                Ok(subnet) if subnet.prefix_len() < discovery::MIN_SUBNET_PREFIX_LEN => {
+                    eprintln!(
+                        "Subnet {} is too big to search, use /{} or smaller",
+                        subnet,
+                        discovery::MIN_SUBNET_PREFIX_LEN
+                    );
+                    process::exit(1);
                 }
-                TargetInquiry { ref device_uuid } => {
-                    let located_device = located_devices.get(&device_uuid).unwrap();
-                    let device =
-                        get_device_status_helper(located_device.ip.clone(), device_uuid.clone())
-                            .await;
-                    *shared_action = TargetResponse {
-                        target: device.unwrap().target.clone(),
-                    };
+                Ok(subnet) => subnets.push(subnet),
+                Err(e) => {
+                    eprintln!("Bad subnet '{}': {}", value, e);
+                    process::exit(1);
                 }
-                TargetResponse { .. } => {}
-                NoUpdate => {}
+            }
+        }
+        overrides.subnets = Some(subnets);
+    }
+    if let Some(secs) = sub_matches.get_one::<String>("rediscover-interval") {
+        match secs.parse() {
+            Ok(secs) if secs > 0 => overrides.rediscover_interval = Some(secs),
+            _ => {
+                eprintln!("Bad rediscover interval '{}'", secs);
+                process::exit(1);
             }
         }
     }
+    if let Some(path) = sub_matches.get_one::<String>("state-file") {
+        overrides.state_file = Some(PathBuf::from(path));
+    }
+    overrides
+}
+
+/// Handles every request submitted on the command bus until every sender is gone or
+/// the hub shuts down, each on its own task so requests for other devices don't wait
+/// on a slow node. Requests for the same device or group are still handled one at a time.
+/// Devices changed by a command have their new state published on `events`, and
+/// everything heard from a node goes into the shadow.
+///
+/// Once the shutdown starts new requests are turned away, but the ones already
+/// submitted are still seen through.
+async fn business_logic(
+    shared_config: Arc<Mutex<SharedConfig>>,
+    registry: DeviceRegistry,
+    shadow: DeviceShadow,
+    events: HubEvents,
+    mut bus_receiver: mpsc::Receiver<BusMessage>,
+    shutdown: Shutdown,
+) {
+    let mut closed = false;
+    let mut in_flight = task::JoinSet::new();
+    let device_turns = DeviceTurns::default();
+    loop {
+        let message = tokio::select! {
+            message = bus_receiver.recv() => message,
+            // Reap the finished requests as they go so they don't pile up
+            Some(_) = in_flight.join_next(), if !in_flight.is_empty() => continue,
+            _ = shutdown.triggered(), if !closed => {
+                bus_receiver.close();
+                closed = true;
+                continue;
+            }
+        };
+        let BusMessage { request, reply } = match message {
+            Some(message) => message,
+            None => break,
+        };
+        // Each request gets its own task so a slow node only holds up its own callers
+        let shared_config = shared_config.clone();
+        let registry = registry.clone();
+        let shadow = shadow.clone();
+        let events = events.clone();
+        let device_turns = device_turns.clone();
+        let turn = device_turns.turn(request.device_uuid());
+        in_flight.spawn(async move {
+            let _turn = turn.lock().await;
+            let result = handle_request(
+                &shared_config,
+                &registry,
+                &shadow,
+                &events,
+                &device_turns,
+                request,
+            )
+            .await;
+            // The caller may have given up waiting, that's fine
+            let _ = reply.send(result);
+        });
+    }
+    while in_flight.join_next().await.is_some() {}
+}
+
+/// Carries out a single request from the bus
+async fn handle_request(
+    shared_config: &Arc<Mutex<SharedConfig>>,
+    registry: &DeviceRegistry,
+    shadow: &DeviceShadow,
+    events: &HubEvents,
+    device_turns: &DeviceTurns,
+    request: HubRequest,
+) -> HubResult {
+    match request {
+        HubRequest::Command {
+            device_uuid,
+            action,
+        } => {
+            let change = Change::Action(action);
+            run_command(
+                shared_config,
+                registry,
+                shadow,
+                events,
+                device_turns,
+                &device_uuid,
+                &change,
+            )
+            .await
+        }
+        HubRequest::Adjust {
+            device_uuid,
+            adjustment,
+        } => {
+            let change = Change::Adjust(adjustment);
+            run_command(
+                shared_config,
+                registry,
+                shadow,
+                events,
+                device_turns,
+                &device_uuid,
+                &change,
+            )
+            .await
+        }
+        HubRequest::TargetInquiry { device_uuid } => {
+            refresh_device(registry, shadow, events, &device_uuid)
+                .await
+                .map(|device| HubResponse::Target {
+                    target: device.target,
+                })
+        }
+        HubRequest::Status { device_uuid } => {
+            refresh_device(registry, shadow, events, &device_uuid)
+                .await
+                .map(|device| HubResponse::Device { device })
+        }
+    }
+}
+
+/// A lock for every device or group requests have been made for, so requests for the
+/// same one take turns while the rest run alongside them. A cheap to clone handle, group
+/// commands also take the turn of every member.
+#[derive(Debug, Clone, Default)]
+struct DeviceTurns {
+    turns: Arc<std::sync::Mutex<HashMap<Uuid, Arc<Mutex<()>>>>>,
+}
+
+impl DeviceTurns {
+    fn turn(&self, uuid: Uuid) -> Arc<Mutex<()>> {
+        let mut turns = self
+            .turns
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Locks nobody is holding or waiting on anymore can go
+        turns.retain(|_, turn| Arc::strong_count(turn) > 1);
+        turns.entry(uuid).or_default().clone()
+    }
 }
 
-async fn update_device(ip: &String, uuid: &Uuid, action: &Action) {
-    let target = match action.get_target() {
-        Some(t) => t.to_string(),
-        None => "".to_string(),
+/// Asks the device's node for its current state and puts it in the shadow, publishing
+/// it when it changed since the hub last heard, unless the device is offline
+async fn refresh_device(
+    registry: &DeviceRegistry,
+    shadow: &DeviceShadow,
+    events: &HubEvents,
+    device_uuid: &Uuid,
+) -> Result<Device, HubError> {
+    let located_device = match registry.get(device_uuid).await {
+        Some(located_device) => located_device,
+        None => return Err(HubError::UnknownDevice { uuid: *device_uuid }),
     };
+    if !located_device.online {
+        return Err(offline(&located_device));
+    }
+    let device = devices::get_device_status(&located_device.ip, device_uuid).await?;
+    if shadow.update(&device).await {
+        events.publish(HubEvent::DeviceState {
+            device: device.clone(),
+        });
+    }
+    Ok(device)
+}
 
-    let url = format!(
-        "http://{}/command?uuid={}&action={}&target={}",
-        &ip,
-        &uuid.to_string(),
-        &action.to_str().to_string(),
-        &target,
-    );
-    dbg!(&url);
-    reqwest::get(&url).await.unwrap();
+/// Makes the change to the device, or to every member when the uuid is a group's,
+/// publishing the new state of every device it changed and keeping the shadow up to date
+async fn run_command(
+    shared_config: &Arc<Mutex<SharedConfig>>,
+    registry: &DeviceRegistry,
+    shadow: &DeviceShadow,
+    events: &HubEvents,
+    device_turns: &DeviceTurns,
+    device_uuid: &Uuid,
+    change: &Change,
+) -> HubResult {
+    let group = groups::find(&shared_config.lock().await.hub, device_uuid);
+    if let Some(group) = group {
+        return Ok(run_group_command(
+            shared_config,
+            registry,
+            shadow,
+            events,
+            device_turns,
+            &group,
+            change,
+        )
+        .await);
+    }
+    match registry.get(device_uuid).await {
+        Some(located_device) => {
+            let range = shared_config.lock().await.hub.target_range(&located_device);
+            let device = apply_change(&located_device, &range, change).await?;
+            shadow.update(&device).await;
+            events.publish(HubEvent::DeviceState {
+                device: device.clone(),
+            });
+            Ok(HubResponse::Device { device })
+        }
+        None => Err(HubError::UnknownDevice { uuid: *device_uuid }),
+    }
 }
 
-/// Needed so that the ip and uuid are owned and thus not dropped
-async fn get_device_status_helper(ip: String, uuid: Uuid) -> Result<Device, String> {
-    devices::get_device_status(&ip, &uuid).await
+/// Sends the device the action, or for an adjustment the target it works out to from
+/// the device's current one. Targets set outright have to be in the device's range,
+/// adjustments are kept in it. Offline devices aren't tried at all.
+async fn apply_change(
+    located_device: &LocatedDevice,
+    range: &TargetRange,
+    change: &Change,
+) -> Result<Device, HubError> {
+    if !located_device.online {
+        return Err(offline(located_device));
+    }
+    let uuid = located_device.device.uuid;
+    let action = match change {
+        Change::Action(Action::Set { target }) if !range.contains(*target) => {
+            return Err(HubError::TargetOutOfRange {
+                name: located_device.device.name.clone(),
+                target: *target,
+                min: range.min,
+                max: range.max,
+            });
+        }
+        Change::Action(action) => *action,
+        Change::Adjust(adjustment) => match adjustment.absolute(range) {
+            Some(target) => Action::Set { target },
+            // Only steps need to know where the device is now
+            None => {
+                let current = devices::get_device_status(&located_device.ip, &uuid).await?;
+                Action::Set {
+                    target: adjustment.apply(current.target, range),
+                }
+            }
+        },
+    };
+    devices::send_command(&located_device.ip, &uuid, &action).await
+}
+
+/// Why nothing was sent to a device whose node isn't answering health checks
+fn offline(located_device: &LocatedDevice) -> HubError {
+    HubError::DeviceOffline {
+        name: located_device.device.name.clone(),
+        ip: located_device.ip.clone(),
+    }
+}
+
+/// Makes the change to every member of the group at once, one failing doesn't stop
+/// the others
+async fn run_group_command(
+    shared_config: &Arc<Mutex<SharedConfig>>,
+    registry: &DeviceRegistry,
+    shadow: &DeviceShadow,
+    events: &HubEvents,
+    device_turns: &DeviceTurns,
+    group: &Group,
+    change: &Change,
+) -> HubResponse {
+    let mut members: Vec<LocatedDevice> = registry
+        .snapshot()
+        .await
+        .into_values()
+        .filter(|located_device| group.contains(located_device))
+        .collect();
+    // Wait for every member's turn so commands sent to them on their own don't race this
+    // one. Always taken in uuid order, so two groups can't each hold what the other waits on.
+    let mut member_uuids: Vec<Uuid> = members
+        .iter()
+        .map(|located_device| located_device.device.uuid)
+        .collect();
+    member_uuids.sort();
+    let mut member_turns = Vec::new();
+    for uuid in member_uuids {
+        member_turns.push(device_turns.turn(uuid).lock_owned().await);
+    }
+    members.sort_by(|a, b| a.device.name.cmp(&b.device.name));
+    let ranges: Vec<TargetRange> = {
+        let shared_config = shared_config.lock().await;
+        members
+            .iter()
+            .map(|located_device| shared_config.hub.target_range(located_device))
+            .collect()
+    };
+    let results = join_all(
+        members
+            .iter()
+            .zip(&ranges)
+            .map(|(located_device, range)| async move {
+                let uuid = located_device.device.uuid;
+                let result = apply_change(located_device, range, change).await;
+                if let Ok(device) = &result {
+                    shadow.update(device).await;
+                    events.publish(HubEvent::DeviceState {
+                        device: device.clone(),
+                    });
+                }
+                MemberResult {
+                    uuid,
+                    name: located_device.device.name.clone(),
+                    device: result.as_ref().ok().cloned(),
+                    error: result.err(),
+                }
+            }),
+    )
+    .await;
+    HubResponse::Group { results }
 }
@@ -0,0 +1,50 @@
+//! Runs the hub's background work, polling, health checks and rediscovery, on an
+//! interval from the config that follows reloads.
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{watch, Mutex};
+use tokio::time::{sleep_until, Instant};
+
+use crate::config::HubConfig;
+use crate::thread_sharing::SharedConfig;
+
+/// Runs `task` every `interval` of the shared config, forever
+///
+/// The first run is one interval after this is called. The wait is counted from when
+/// the task last started, so a reload only moves the next run to match the new
+/// interval instead of starting the wait over.
+///
+/// - 'interval': picks the interval out of the config, read again on every reload
+/// - 'config_changes': notified on every reload, see `ConfigReloader::subscribe`
+pub async fn every<T, F>(
+    shared_config: &Arc<Mutex<SharedConfig>>,
+    interval: fn(&HubConfig) -> Duration,
+    mut config_changes: watch::Receiver<u64>,
+    mut task: T,
+) where
+    T: FnMut() -> F,
+    F: Future<Output = ()>,
+{
+    let mut last_run = Instant::now();
+    let mut deadline = last_run + interval(&shared_config.lock().await.hub);
+    let mut watching_config = true;
+    loop {
+        tokio::select! {
+            _ = sleep_until(deadline) => {}
+            changed = config_changes.changed(), if watching_config => {
+                match changed {
+                    Ok(()) => deadline = last_run + interval(&shared_config.lock().await.hub),
+                    // Nobody can reload anymore, carry on with what we have
+                    Err(_) => watching_config = false,
+                }
+                continue;
+            }
+        }
+
+        last_run = Instant::now();
+        task().await;
+        deadline = last_run + interval(&shared_config.lock().await.hub);
+    }
+}
@@ -0,0 +1,208 @@
+//! The live set of located devices, shared by the servers, the business logic and the
+//! rediscovery task.
+//!
+//! The registry writes itself out to a state file on every change, so the next start
+//! can use the last known devices straight away.
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bluer::Uuid;
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::devices::LocatedDevice;
+use crate::thread_sharing::{HubEvent, HubEvents};
+
+/// What changed when new discovery results were merged in
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MergeSummary {
+    pub added: Vec<Uuid>,
+    pub moved: Vec<Uuid>,
+    pub back_online: Vec<Uuid>,
+}
+
+/// A cheap to clone handle on the shared devices
+#[derive(Debug, Clone)]
+pub struct DeviceRegistry {
+    devices: Arc<RwLock<HashMap<Uuid, LocatedDevice>>>,
+    state_file: Arc<PathBuf>,
+    events: HubEvents,
+}
+
+impl DeviceRegistry {
+    /// Creates a registry from the devices saved in the state file, which is kept up
+    /// to date from then on, publishing what changes on `events`
+    ///
+    /// A missing or unreadable state file just means starting out empty.
+    pub fn load(state_file: PathBuf, events: HubEvents) -> DeviceRegistry {
+        let devices = match fs::read_to_string(&state_file) {
+            Ok(text) => match serde_json::from_str(&text) {
+                Ok(devices) => devices,
+                Err(e) => {
+                    eprintln!("Ignoring the state file {}: {}", state_file.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+        DeviceRegistry {
+            devices: Arc::new(RwLock::new(devices)),
+            state_file: Arc::new(state_file),
+            events,
+        }
+    }
+
+    /// Gets a device by its uuid, whether it's online or not
+    pub async fn get(&self, uuid: &Uuid) -> Option<LocatedDevice> {
+        self.devices.read().await.get(uuid).cloned()
+    }
+
+    /// A copy of every device as it stands right now
+    pub async fn snapshot(&self) -> HashMap<Uuid, LocatedDevice> {
+        self.devices.read().await.clone()
+    }
+
+    /// The name and uuid of every device
+    pub async fn names(&self) -> Vec<(String, Uuid)> {
+        self.devices
+            .read()
+            .await
+            .iter()
+            .map(|(u, ld)| (ld.device.name.clone(), *u))
+            .collect()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.devices.read().await.len()
+    }
+
+    /// Adds or updates the devices a node announced for itself, leaving every other
+    /// device as it is
+    pub async fn register(&self, announced: HashMap<Uuid, LocatedDevice>) -> MergeSummary {
+        let mut summary = MergeSummary::default();
+        let mut changed = false;
+        let mut devices = self.devices.write().await;
+        for (uuid, announced_device) in announced {
+            match devices.insert(uuid, announced_device.clone()) {
+                Some(previous) => {
+                    changed |= previous != announced_device;
+                    if previous.ip != announced_device.ip {
+                        summary.moved.push(uuid);
+                    } else if !previous.online {
+                        summary.back_online.push(uuid);
+                    }
+                }
+                None => {
+                    changed = true;
+                    summary.added.push(uuid);
+                }
+            }
+        }
+        if changed {
+            self.save(&devices);
+        }
+        self.announce(&summary, &devices);
+        summary
+    }
+
+    /// Folds the results of a discovery run into the registry
+    ///
+    /// Found devices are added or have their ip updated and are marked online. Devices
+    /// that weren't found are left as they are, whether they're still there is up to
+    /// the health checks, since nodes that registered themselves may well be somewhere
+    /// discovery doesn't look.
+    pub async fn merge(&self, found: HashMap<Uuid, LocatedDevice>) -> MergeSummary {
+        let mut summary = MergeSummary::default();
+        let mut changed = false;
+        let mut devices = self.devices.write().await;
+        for (uuid, found_device) in found {
+            match devices.get_mut(&uuid) {
+                Some(located_device) => {
+                    if located_device.ip != found_device.ip {
+                        summary.moved.push(uuid);
+                    }
+                    if !located_device.online {
+                        summary.back_online.push(uuid);
+                    }
+                    if *located_device != found_device {
+                        changed = true;
+                        *located_device = found_device;
+                    }
+                }
+                None => {
+                    changed = true;
+                    summary.added.push(uuid);
+                    devices.insert(uuid, found_device);
+                }
+            }
+        }
+        if changed {
+            self.save(&devices);
+        }
+        self.announce(&summary, &devices);
+        summary
+    }
+
+    /// Publishes an event for everything in the summary
+    fn announce(&self, summary: &MergeSummary, devices: &HashMap<Uuid, LocatedDevice>) {
+        let ip = |uuid: &Uuid| match devices.get(uuid) {
+            Some(located_device) => located_device.ip.clone(),
+            None => String::new(),
+        };
+        for uuid in summary.added.iter() {
+            self.events.publish(HubEvent::DeviceAdded {
+                uuid: *uuid,
+                ip: ip(uuid),
+            });
+        }
+        for uuid in summary.moved.iter() {
+            self.events.publish(HubEvent::DeviceMoved {
+                uuid: *uuid,
+                ip: ip(uuid),
+            });
+        }
+        for uuid in summary.back_online.iter() {
+            self.events.publish(HubEvent::DeviceOnline { uuid: *uuid });
+        }
+    }
+
+    /// Marks the device online or offline, publishing it if that's a change
+    ///
+    /// Returns whether it was a change.
+    pub async fn set_online(&self, uuid: &Uuid, online: bool) -> bool {
+        let mut devices = self.devices.write().await;
+        match devices.get_mut(uuid) {
+            Some(located_device) if located_device.online != online => {
+                located_device.online = online;
+            }
+            _ => return false,
+        }
+        self.save(&devices);
+        match online {
+            true => self.events.publish(HubEvent::DeviceOnline { uuid: *uuid }),
+            false => self.events.publish(HubEvent::DeviceOffline { uuid: *uuid }),
+        }
+        true
+    }
+
+    /// Writes the devices to the state file
+    fn save(&self, devices: &HashMap<Uuid, LocatedDevice>) {
+        if let Err(e) = write_state_file(&self.state_file, devices) {
+            eprintln!(
+                "Failed to save the devices to {}: {}",
+                self.state_file.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Writes to a temporary file first so a crash mid-write can't leave a truncated state file
+fn write_state_file(path: &Path, devices: &HashMap<Uuid, LocatedDevice>) -> std::io::Result<()> {
+    let text = serde_json::to_string_pretty(devices)?;
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, text)?;
+    fs::rename(&temp_path, path)
+}
@@ -0,0 +1,174 @@
+//! The hub's own copy of every device's last known state, so reads don't have to wait
+//! on a node.
+//!
+//! The shadow is kept up to date after every command that goes through and by polling
+//! each online device's `/status` every `shadow.poll_interval`. Readers get how old the
+//! state is along with it and can ask the node instead when that's too old.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bluer::Uuid;
+use device::Device;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{watch, Mutex, RwLock};
+use tokio::time::timeout;
+
+use crate::devices::{self, LocatedDevice};
+use crate::error::HubError;
+use crate::periodic;
+use crate::registry::DeviceRegistry;
+use crate::thread_sharing::{
+    CommandBus, HubEvent, HubEvents, HubRequest, HubResponse, SharedConfig,
+};
+
+/// How many devices get polled at once
+const POLL_CONCURRENCY: usize = 16;
+/// How long a device has to answer a poll before it's skipped until next time
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A device's last known state and when the hub heard it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShadowState {
+    pub device: Device,
+    /// Seconds since the Unix epoch
+    pub updated_at: u64,
+    /// Seconds since then, as of being read
+    pub age_secs: u64,
+}
+
+impl ShadowState {
+    /// The state as the node just gave it
+    pub fn fresh(device: Device) -> ShadowState {
+        ShadowState::new(device, SystemTime::now())
+    }
+
+    fn new(device: Device, updated: SystemTime) -> ShadowState {
+        let updated_at = match updated.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => since_epoch.as_secs(),
+            Err(_) => 0,
+        };
+        let age_secs = match updated.elapsed() {
+            Ok(age) => age.as_secs(),
+            Err(_) => 0,
+        };
+        ShadowState {
+            device,
+            updated_at,
+            age_secs,
+        }
+    }
+}
+
+/// A cheap to clone handle on the shared device states
+#[derive(Debug, Clone)]
+pub struct DeviceShadow {
+    states: Arc<RwLock<HashMap<Uuid, (Device, SystemTime)>>>,
+}
+
+impl DeviceShadow {
+    pub fn new() -> DeviceShadow {
+        DeviceShadow {
+            states: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// The device's last known state, if the hub has heard it yet
+    pub async fn get(&self, uuid: &Uuid) -> Option<ShadowState> {
+        self.states
+            .read()
+            .await
+            .get(uuid)
+            .map(|(device, updated)| ShadowState::new(device.clone(), *updated))
+    }
+
+    /// Records what a node just said the device's state is, returning whether the hub
+    /// knew it at a different target before
+    pub async fn update(&self, device: &Device) -> bool {
+        let previous = self
+            .states
+            .write()
+            .await
+            .insert(device.uuid, (device.clone(), SystemTime::now()));
+        match previous {
+            Some((previous, _)) => previous.target != device.target,
+            None => false,
+        }
+    }
+}
+
+/// The device's state from the shadow, or straight from its node when `refresh` is set
+/// or the hub hasn't heard it yet
+pub async fn read(
+    shadow: &DeviceShadow,
+    command_bus: &CommandBus,
+    device_uuid: Uuid,
+    refresh: bool,
+) -> Result<ShadowState, HubError> {
+    if !refresh {
+        if let Some(state) = shadow.get(&device_uuid).await {
+            return Ok(state);
+        }
+    }
+    match command_bus
+        .submit(HubRequest::Status { device_uuid })
+        .await?
+    {
+        HubResponse::Device { device } => Ok(ShadowState::fresh(device)),
+        other => Err(HubError::HubUnavailable {
+            reason: format!("Expected the device's state, got {:?}", other),
+        }),
+    }
+}
+
+/// Polls every online device every `shadow.poll_interval`, so changes made at the
+/// devices themselves make it into the shadow and out to anyone watching, on the
+/// schedule kept by `periodic::every`
+pub async fn poll_forever(
+    shadow: DeviceShadow,
+    registry: DeviceRegistry,
+    events: HubEvents,
+    shared_config: Arc<Mutex<SharedConfig>>,
+    config_changes: watch::Receiver<u64>,
+) {
+    poll_now(&shadow, &registry, &events).await;
+    periodic::every(
+        &shared_config,
+        |hub| Duration::from_secs(hub.shadow.poll_interval),
+        config_changes,
+        || poll_now(&shadow, &registry, &events),
+    )
+    .await
+}
+
+/// Asks every online device for its state right away, publishing the ones that changed
+pub async fn poll_now(shadow: &DeviceShadow, registry: &DeviceRegistry, events: &HubEvents) {
+    let online: Vec<LocatedDevice> = registry
+        .snapshot()
+        .await
+        .into_values()
+        .filter(|located_device| located_device.online)
+        .collect();
+    stream::iter(online)
+        .map(|located_device| async move {
+            let uuid = located_device.device.uuid;
+            match timeout(
+                POLL_TIMEOUT,
+                devices::get_device_status(&located_device.ip, &uuid),
+            )
+            .await
+            {
+                Ok(Ok(device)) => {
+                    if shadow.update(&device).await {
+                        events.publish(HubEvent::DeviceState { device });
+                    }
+                }
+                Ok(Err(e)) => eprintln!("Couldn't poll {}: {}", located_device.device.name, e),
+                Err(_) => eprintln!("Polling {} timed out", located_device.device.name),
+            }
+        })
+        .buffer_unordered(POLL_CONCURRENCY)
+        .collect::<Vec<()>>()
+        .await;
+}
@@ -0,0 +1,60 @@
+//! Lets any part of the hub start a shutdown and every other part wind down when it does.
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// How long everything gets to wind down before the hub exits anyway
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A cheap to clone handle on the hub's one shutdown
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    started: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (started, _) = watch::channel(false);
+        Shutdown {
+            started: Arc::new(started),
+        }
+    }
+
+    /// Starts shutting down, asking again once it's started does nothing
+    pub fn trigger(&self) {
+        self.started
+            .send_if_modified(|started| !std::mem::replace(started, true));
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Waits until a shutdown has been started, returning straight away if it already has
+    pub async fn triggered(&self) {
+        let mut started = self.started.subscribe();
+        // The sender lives as long as self, so this can't fail
+        let _ = started.wait_for(|started| *started).await;
+    }
+}
+
+/// Starts the shutdown on SIGINT or SIGTERM
+pub async fn shutdown_on_signals(shutdown: Shutdown) {
+    let (mut interrupts, mut terminates) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupts), Ok(terminates)) => (interrupts, terminates),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Can't listen for SIGINT and SIGTERM: {}", e);
+            return;
+        }
+    };
+    tokio::select! {
+        _ = interrupts.recv() => println!("Got SIGINT, shutting down"),
+        _ = terminates.recv() => println!("Got SIGTERM, shutting down"),
+    }
+    shutdown.trigger();
+}
@@ -1,35 +1,185 @@
+use std::fmt;
+
 use bluer::Uuid;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 use device;
 
+use crate::command_parser::Adjustment;
+use crate::config::HubConfig;
+use crate::error::HubError;
+
+/// How many requests can be waiting on the bus before submitters have to wait
+const COMMAND_BUS_CAPACITY: usize = 64;
+/// How many events a slow subscriber can fall behind before it starts missing some
+const EVENT_CAPACITY: usize = 64;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SharedConfig {
     pub verbosity: String,
+    pub hub: HubConfig,
 }
 
+/// A request that the HTTP server, BLE server or control socket wants the
+/// business logic to carry out
 #[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-pub enum SharedGetRequest {
+pub enum HubRequest {
     Command {
         device_uuid: Uuid,
         action: device::Action,
     },
-    NoUpdate,
-}
-
-#[derive(Debug, Copy, Clone, Deserialize, Serialize)]
-pub enum SharedBLEAction {
-    Command {
+    TargetInquiry {
         device_uuid: Uuid,
-        action: device::Action,
     },
-    TargetInquiry {
+    /// Asks the node for the device's whole current state
+    Status {
+        device_uuid: Uuid,
+    },
+    /// Sets the device to a target worked out from its current one
+    Adjust {
         device_uuid: Uuid,
+        adjustment: Adjustment,
     },
-    TargetResponse {
+}
+
+impl HubRequest {
+    /// The device or group the request is for
+    pub fn device_uuid(&self) -> Uuid {
+        match *self {
+            HubRequest::Command { device_uuid, .. }
+            | HubRequest::TargetInquiry { device_uuid }
+            | HubRequest::Status { device_uuid }
+            | HubRequest::Adjust { device_uuid, .. } => device_uuid,
+        }
+    }
+}
+
+/// What the business logic sends back once a `HubRequest` has been handled
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum HubResponse {
+    Done,
+    Device {
+        device: device::Device,
+    },
+    Target {
         target: usize,
     },
-    NoUpdate,
+    /// How a command sent to a group went for each of its members
+    Group {
+        results: Vec<MemberResult>,
+    },
+}
+
+/// How a command went for one member of a group, the device's new state or why it failed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemberResult {
+    pub uuid: Uuid,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<device::Device>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<HubError>,
+}
+
+pub type HubResult = Result<HubResponse, HubError>;
+
+/// A request along with the channel its result should be sent back on
+pub struct BusMessage {
+    pub request: HubRequest,
+    pub reply: oneshot::Sender<HubResult>,
+}
+
+/// The sending half of the command bus, cheap to clone for every producer
+#[derive(Debug, Clone)]
+pub struct CommandBus {
+    sender: mpsc::Sender<BusMessage>,
+}
+
+impl CommandBus {
+    /// Creates a bus along with the receiver that the business logic consumes
+    pub fn new() -> (CommandBus, mpsc::Receiver<BusMessage>) {
+        let (sender, receiver) = mpsc::channel(COMMAND_BUS_CAPACITY);
+        (CommandBus { sender }, receiver)
+    }
+
+    /// Queues up a request and waits for its result
+    pub async fn submit(&self, request: HubRequest) -> HubResult {
+        let (reply, response) = oneshot::channel();
+        if self
+            .sender
+            .send(BusMessage { request, reply })
+            .await
+            .is_err()
+        {
+            return Err(HubError::HubUnavailable {
+                reason: "The command bus is closed".to_string(),
+            });
+        }
+        match response.await {
+            Ok(result) => result,
+            Err(_) => Err(HubError::HubUnavailable {
+                reason: "The request was dropped before it was handled".to_string(),
+            }),
+        }
+    }
+}
+
+/// Something that happened to a device, for anyone keeping an eye on the hub
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+// The names are what watchers see on the wire, e.g. "device_added", so they keep the prefix
+#[allow(clippy::enum_variant_names)]
+pub enum HubEvent {
+    /// A device was seen for the first time
+    DeviceAdded { uuid: Uuid, ip: String },
+    /// A known device turned up at a new address
+    DeviceMoved { uuid: Uuid, ip: String },
+    /// A device stopped answering
+    DeviceOffline { uuid: Uuid },
+    /// A device that had gone offline is answering again
+    DeviceOnline { uuid: Uuid },
+    /// A device's node is answering slowly or has missed some health checks
+    DeviceDegraded { uuid: Uuid },
+    /// A device's state changed because of a command
+    DeviceState { device: device::Device },
+}
+
+impl fmt::Display for HubEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use HubEvent::*;
+        match self {
+            DeviceAdded { uuid, ip } => write!(f, "Found new device {} at {}", uuid, ip),
+            DeviceMoved { uuid, ip } => write!(f, "Device {} moved to {}", uuid, ip),
+            DeviceOffline { uuid } => write!(f, "Device {} went offline", uuid),
+            DeviceOnline { uuid } => write!(f, "Device {} is back online", uuid),
+            DeviceDegraded { uuid } => write!(f, "Device {} is answering poorly", uuid),
+            DeviceState { device } => write!(f, "{} is now at {}", device.name, device.target),
+        }
+    }
+}
+
+/// Fans `HubEvent`s out to every subscriber, cheap to clone for every publisher
+#[derive(Debug, Clone)]
+pub struct HubEvents {
+    sender: broadcast::Sender<HubEvent>,
+}
+
+impl HubEvents {
+    pub fn new() -> HubEvents {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        HubEvents { sender }
+    }
+
+    /// Sends the event to everyone currently subscribed
+    pub fn publish(&self, event: HubEvent) {
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<HubEvent> {
+        self.sender.subscribe()
+    }
 }
 /*
 #[derive(Debug, Clone, Deserialize, Serialize)]